@@ -0,0 +1,129 @@
+// Measures playlist and segment lookup latency while many readers hammer the
+// segment ring and the producer keeps publishing new segments.
+//
+//     cargo bench --bench hls_snapshot
+
+// Only a part of each included module is exercised here
+#![allow(dead_code)]
+
+extern crate arc_swap;
+extern crate brotli;
+extern crate bytes;
+extern crate flate2;
+extern crate futures;
+extern crate hyper;
+
+#[path = "../src/compression.rs"]
+mod compression;
+#[path = "../src/hls.rs"]
+mod hls;
+#[path = "../src/lazybytes.rs"]
+mod lazybytes;
+
+use compression::ContentCoding;
+use hls::Hls;
+use lazybytes::LazyBytes;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const READER_THREADS: usize = 64;
+const ITERATIONS_PER_READER: usize = 20_000;
+const SEGMENT_INTERVAL_US: u64 = 500;
+
+fn nanos(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000_000 + duration.subsec_nanos() as u64
+}
+
+fn permille(sorted: &[u64], permille: usize) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    sorted[(sorted.len() - 1) * permille / 1000]
+}
+
+fn report(name: &str, mut latencies: Vec<u64>) {
+    latencies.sort();
+    println!(
+        "{:<16} {:>10} ops  p50 {:>7} ns  p99 {:>7} ns  p99.9 {:>8} ns  max {:>9} ns",
+        name,
+        latencies.len(),
+        permille(&latencies, 500),
+        permille(&latencies, 990),
+        permille(&latencies, 999),
+        permille(&latencies, 1000)
+    );
+}
+
+fn main() {
+    let hls = Hls::new(10, 1, 350);
+    let stop = Arc::new(AtomicBool::new(false));
+    let published = Arc::new(AtomicUsize::new(0));
+
+    let producer = {
+        let hls = hls.clone();
+        let stop = stop.clone();
+        let published = published.clone();
+        thread::spawn(move || {
+            let mut add_latencies = Vec::new();
+            while !stop.load(Ordering::Relaxed) {
+                let lazy_bytes = LazyBytes::new();
+                lazy_bytes.append(bytes::Bytes::from(vec![0x47u8; 188 * 100]));
+                lazy_bytes.complete();
+                let start = Instant::now();
+                hls.add_new_segment(0, 350, lazy_bytes, false);
+                add_latencies.push(nanos(start.elapsed()));
+                published.fetch_add(1, Ordering::Relaxed);
+                thread::sleep(Duration::from_micros(SEGMENT_INTERVAL_US));
+            }
+            add_latencies
+        })
+    };
+
+    let start = Instant::now();
+    let readers = (0..READER_THREADS)
+        .map(|_| {
+            let hls = hls.clone();
+            let published = published.clone();
+            thread::spawn(move || {
+                let mut playlist_latencies = Vec::with_capacity(ITERATIONS_PER_READER);
+                let mut segment_latencies = Vec::with_capacity(ITERATIONS_PER_READER);
+                for _ in 0..ITERATIONS_PER_READER {
+                    let start = Instant::now();
+                    let playlist = hls.snapshot().playlist().encoded(ContentCoding::Identity);
+                    playlist_latencies.push(nanos(start.elapsed()));
+                    assert!(!playlist.is_empty());
+
+                    let index = published.load(Ordering::Relaxed) as u64;
+                    let start = Instant::now();
+                    let segment = hls.snapshot().read_segment(index);
+                    segment_latencies.push(nanos(start.elapsed()));
+                    drop(segment);
+                }
+                (playlist_latencies, segment_latencies)
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut playlist_latencies = Vec::new();
+    let mut segment_latencies = Vec::new();
+    for reader in readers {
+        let (playlist, segment) = reader.join().expect("Failed to join reader thread");
+        playlist_latencies.extend(playlist);
+        segment_latencies.extend(segment);
+    }
+    let elapsed = start.elapsed();
+    stop.store(true, Ordering::Relaxed);
+    let add_latencies = producer.join().expect("Failed to join producer thread");
+
+    println!(
+        "{} reader threads, {} segments published in {} ms",
+        READER_THREADS,
+        published.load(Ordering::Relaxed),
+        nanos(elapsed) / 1_000_000
+    );
+    report("playlist", playlist_latencies);
+    report("segment lookup", segment_latencies);
+    report("add_new_segment", add_latencies);
+}
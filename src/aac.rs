@@ -0,0 +1,168 @@
+use std::os::raw::c_int;
+use std::ptr::{copy_nonoverlapping, null_mut};
+use std::slice::from_raw_parts;
+use ffmpeg_sys::*;
+use libc;
+use error::{check_av, check_null, MediaError};
+
+pub struct AacPacket {
+    pub data: Vec<u8>,
+    pub pts: i64,
+    pub duration: i64,
+}
+
+pub struct AacEncoder {
+    codec_context: *mut AVCodecContext,
+    frame: *mut AVFrame,
+    pending: Vec<Vec<f32>>,
+    sent_samples: i64,
+    pub sample_rate: i32,
+    pub channels: i32,
+}
+
+impl Drop for AacEncoder {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.frame.is_null() {
+                av_frame_free(&mut self.frame);
+            }
+            if !self.codec_context.is_null() {
+                avcodec_free_context(&mut self.codec_context);
+            }
+        }
+    }
+}
+
+impl AacEncoder {
+    pub fn new(
+        sample_rate: i32,
+        channels: i32,
+        bit_rate: i64,
+    ) -> Result<AacEncoder, MediaError> {
+        unsafe {
+            let mut obj = AacEncoder {
+                codec_context: null_mut(),
+                frame: null_mut(),
+                pending: vec![Vec::new(); channels as usize],
+                sent_samples: 0,
+                sample_rate,
+                channels,
+            };
+
+            let codec = avcodec_find_encoder(AVCodecID::AV_CODEC_ID_AAC);
+            if codec.is_null() {
+                return Err(MediaError::Null("avcodec_find_encoder"));
+            }
+
+            obj.codec_context =
+                check_null("avcodec_alloc_context3", avcodec_alloc_context3(codec))?;
+            {
+                let context = &mut *obj.codec_context;
+                context.codec_type = AVMediaType::AVMEDIA_TYPE_AUDIO;
+                context.sample_fmt = AVSampleFormat::AV_SAMPLE_FMT_FLTP;
+                context.sample_rate = sample_rate;
+                context.channels = channels;
+                context.channel_layout = av_get_default_channel_layout(channels) as u64;
+                context.bit_rate = bit_rate;
+                context.time_base.num = 1;
+                context.time_base.den = sample_rate;
+                // MPEG-TS needs the AudioSpecificConfig to generate ADTS headers
+                context.flags |= AV_CODEC_FLAG_GLOBAL_HEADER as c_int;
+            }
+
+            check_av(
+                "avcodec_open2",
+                avcodec_open2(obj.codec_context, codec, null_mut()),
+            )?;
+
+            obj.frame = check_null("av_frame_alloc", av_frame_alloc())?;
+            {
+                let frame = &mut *obj.frame;
+                frame.nb_samples = (*obj.codec_context).frame_size;
+                frame.format = AVSampleFormat::AV_SAMPLE_FMT_FLTP as c_int;
+                frame.channels = channels;
+                frame.channel_layout = (*obj.codec_context).channel_layout;
+                frame.sample_rate = sample_rate;
+            }
+            check_av("av_frame_get_buffer", av_frame_get_buffer(obj.frame, 0))?;
+
+            Ok(obj)
+        }
+    }
+
+    pub fn fill_codec_parameters(
+        &self,
+        codecpar: &mut AVCodecParameters,
+    ) -> Result<(), MediaError> {
+        check_av("avcodec_parameters_from_context", unsafe {
+            avcodec_parameters_from_context(codecpar, self.codec_context)
+        })?;
+        Ok(())
+    }
+
+    // Timestamps the first samples at pts instead of 0. Has no effect once
+    // samples were taken.
+    pub fn skip_to(&mut self, pts: i64) {
+        if self.sent_samples == 0 && self.pending[0].is_empty() {
+            self.sent_samples = pts;
+        }
+    }
+
+    // Takes planar samples and returns the packets that became available.
+    // Packet timestamps are in samples (1 / sample_rate).
+    pub fn encode(&mut self, planes: &[Vec<f32>]) -> Result<Vec<AacPacket>, MediaError> {
+        // Every channel of a frame is copied from its own plane
+        if planes.len() != self.pending.len() {
+            return Err(MediaError::InvalidInput(format!(
+                "AAC encoder got {} planes for {} channels",
+                planes.len(),
+                self.pending.len()
+            )));
+        }
+        for (pending, plane) in self.pending.iter_mut().zip(planes) {
+            pending.extend_from_slice(plane);
+        }
+
+        let frame_size = unsafe { (*self.codec_context).frame_size } as usize;
+        let mut packets = Vec::new();
+        while self.pending[0].len() >= frame_size {
+            unsafe {
+                check_av("av_frame_make_writable", av_frame_make_writable(self.frame))?;
+                for (channel, pending) in self.pending.iter_mut().enumerate() {
+                    copy_nonoverlapping(
+                        pending.as_ptr(),
+                        *(*self.frame).extended_data.offset(channel as isize) as *mut f32,
+                        frame_size,
+                    );
+                    pending.drain(0..frame_size);
+                }
+                (*self.frame).pts = self.sent_samples;
+                check_av(
+                    "avcodec_send_frame",
+                    avcodec_send_frame(self.codec_context, self.frame),
+                )?;
+                self.receive_packets(&mut packets)?;
+            }
+            self.sent_samples += frame_size as i64;
+        }
+        Ok(packets)
+    }
+
+    unsafe fn receive_packets(&mut self, packets: &mut Vec<AacPacket>) -> Result<(), MediaError> {
+        loop {
+            let mut packet: AVPacket = ::std::mem::zeroed();
+            av_init_packet(&mut packet);
+            let r = avcodec_receive_packet(self.codec_context, &mut packet);
+            if r == AVERROR(libc::EAGAIN) || r == AVERROR_EOF {
+                return Ok(());
+            }
+            check_av("avcodec_receive_packet", r)?;
+            packets.push(AacPacket {
+                data: from_raw_parts(packet.data, packet.size as usize).to_vec(),
+                pts: packet.pts,
+                duration: packet.duration,
+            });
+            av_packet_unref(&mut packet);
+        }
+    }
+}
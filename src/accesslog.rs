@@ -0,0 +1,114 @@
+use futures::{Async, Poll, Stream};
+use hyper;
+use hyper::server::Request;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+type BoxedStream = Box<Stream<Item = hyper::Chunk, Error = hyper::Error>>;
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + duration.subsec_nanos() as f64 / 1_000_000.0
+}
+
+// One access log line, written when the response and its body are done with.
+pub struct AccessLog {
+    remote_addr: String,
+    method: String,
+    path: String,
+    started_at: Instant,
+    status: Cell<u16>,
+    responded_at: Cell<Option<Instant>>,
+    first_byte_at: Cell<Option<Instant>>,
+    bytes: Cell<u64>,
+}
+
+impl AccessLog {
+    pub fn new(req: &Request) -> Rc<AccessLog> {
+        Rc::new(AccessLog {
+            remote_addr: req.remote_addr()
+                .map(|addr| addr.to_string())
+                .unwrap_or_else(|| "-".to_owned()),
+            method: req.method().to_string(),
+            path: req.path().to_owned(),
+            started_at: Instant::now(),
+            status: Cell::new(0),
+            responded_at: Cell::new(None),
+            first_byte_at: Cell::new(None),
+            bytes: Cell::new(0),
+        })
+    }
+
+    pub fn respond(&self, status: u16) {
+        self.status.set(status);
+        self.responded_at.set(Some(Instant::now()));
+    }
+
+    fn sent(&self, len: usize) {
+        if self.first_byte_at.get().is_none() {
+            self.first_byte_at.set(Some(Instant::now()));
+        }
+        self.bytes.set(self.bytes.get() + len as u64);
+    }
+}
+
+impl Drop for AccessLog {
+    fn drop(&mut self) {
+        let now = Instant::now();
+        // Without a body the headers are the first bytes
+        let first_byte_at = self.first_byte_at
+            .get()
+            .or(self.responded_at.get())
+            .unwrap_or(now);
+        info!(
+            target: "access",
+            "remote={} method={} path={:?} status={} bytes={} duration_ms={:.3} ttfb_ms={:.3}",
+            self.remote_addr,
+            self.method,
+            self.path,
+            self.status.get(),
+            self.bytes.get(),
+            millis(now - self.started_at),
+            millis(first_byte_at - self.started_at)
+        );
+    }
+}
+
+// Response body that reports what it sends to the access log attached to it.
+// hyper does not hand the body back once it is set on a response, so the log
+// is attached afterwards through a shared reference.
+pub struct Body {
+    stream: BoxedStream,
+    access_log: RefCell<Option<Rc<AccessLog>>>,
+}
+
+impl Body {
+    pub fn new<S>(stream: S) -> Body
+    where
+        S: Stream<Item = hyper::Chunk, Error = hyper::Error> + 'static,
+    {
+        Body {
+            stream: Box::new(stream),
+            access_log: RefCell::new(None),
+        }
+    }
+
+    pub fn attach(&self, access_log: Rc<AccessLog>) {
+        *self.access_log.borrow_mut() = Some(access_log);
+    }
+}
+
+impl Stream for Body {
+    type Item = hyper::Chunk;
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let poll = self.stream.poll()?;
+        if let Async::Ready(Some(ref chunk)) = poll {
+            if let Some(ref access_log) = *self.access_log.borrow() {
+                access_log.sent(chunk.len());
+            }
+        }
+        Ok(poll)
+    }
+}
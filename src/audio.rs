@@ -0,0 +1,130 @@
+use aac::AacEncoder;
+use error::MediaError;
+use lazybytes::LazyBytes;
+use metrics::Metrics;
+use mpegts::MpegTs;
+use renditions::Renditions;
+use std::sync::Arc;
+use tone::ToneGenerator;
+
+const AUDIO_SAMPLE_RATE: i32 = 48000;
+const AUDIO_CHANNELS: i32 = 2;
+
+struct AudioTrack {
+    mpeg_ts: MpegTs,
+    // Of the segment in progress
+    lazy_bytes: Arc<LazyBytes>,
+    aac_encoder: AacEncoder,
+    tone_generator: ToneGenerator,
+    planes: Vec<Vec<f32>>,
+}
+
+// The tone of every audio rendition, segmented along with the video.
+pub struct AudioTracks {
+    tracks: Vec<AudioTrack>,
+    generation: u64,
+}
+
+impl AudioTracks {
+    // Nothing is published until the caller calls publish. Playlists are
+    // written with the generation of the caller.
+    pub fn new(renditions: &Renditions, generation: u64) -> Result<AudioTracks, MediaError> {
+        let mut tracks = Vec::new();
+        for audio in &renditions.audios {
+            let aac_encoder =
+                AacEncoder::new(AUDIO_SAMPLE_RATE, AUDIO_CHANNELS, audio.bandwidth as i64)?;
+            let lazy_bytes = LazyBytes::new();
+            tracks.push(AudioTrack {
+                mpeg_ts: MpegTs::new(None, Some(&aac_encoder), lazy_bytes.clone())?,
+                lazy_bytes,
+                tone_generator: ToneGenerator::new(
+                    AUDIO_SAMPLE_RATE as u64,
+                    AUDIO_CHANNELS as usize,
+                    audio.tone_hz,
+                ),
+                aac_encoder,
+                planes: Vec::new(),
+            });
+        }
+        Ok(AudioTracks { tracks, generation })
+    }
+
+    // Adds the segments in progress to the playlists, either ahead of
+    // writing them with the segment duration or once their duration is known.
+    pub fn publish(&self, renditions: &Renditions, duration_ms: u64, discontinuity: bool) {
+        for (track, audio) in self.tracks.iter().zip(&renditions.audios) {
+            audio.hls.add_new_segment(
+                self.generation,
+                duration_ms,
+                track.lazy_bytes.clone(),
+                discontinuity,
+            );
+        }
+    }
+
+    // Starts the audio at start_ms of the timeline instead of 0. Only before
+    // anything was written.
+    pub fn start_at(&mut self, start_ms: u64) {
+        for track in &mut self.tracks {
+            track.tone_generator.skip_to(start_ms);
+            let sample_rate = track.aac_encoder.sample_rate as u64;
+            track
+                .aac_encoder
+                .skip_to((start_ms * sample_rate / 1000) as i64);
+        }
+    }
+
+    // Writes the tone from start_ms to end_ms. wall_clock_ms is passed on to
+    // the tone generator.
+    pub fn write(
+        &mut self,
+        start_ms: u64,
+        end_ms: u64,
+        wall_clock_ms: u64,
+    ) -> Result<(), MediaError> {
+        for track in &mut self.tracks {
+            track
+                .tone_generator
+                .generate(&mut track.planes, start_ms, end_ms, wall_clock_ms);
+            for mut aac_packet in track.aac_encoder.encode(&track.planes)? {
+                track
+                    .mpeg_ts
+                    .write_audio(&mut aac_packet, track.aac_encoder.sample_rate)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Completes the segments in progress, which lasted duration_ms, and
+    // starts the next ones without publishing them.
+    pub fn cut(
+        &mut self,
+        renditions: &Renditions,
+        metrics: &Metrics,
+        duration_ms: u64,
+    ) -> Result<(), MediaError> {
+        for (track, audio) in self.tracks.iter_mut().zip(&renditions.audios) {
+            track.mpeg_ts.flush()?;
+            metrics.observe_segment(&audio.name, track.mpeg_ts.written_bytes(), duration_ms);
+            track.lazy_bytes = LazyBytes::new();
+            track.mpeg_ts = MpegTs::new(None, Some(&track.aac_encoder), track.lazy_bytes.clone())?;
+        }
+        Ok(())
+    }
+
+    // Flushes the segments in progress and ends the playlists, see Hls::end
+    // for last_duration_ms. Every playlist is ended even if a flush fails.
+    pub fn finish(
+        &mut self,
+        renditions: &Renditions,
+        last_duration_ms: Option<u64>,
+    ) -> Result<(), MediaError> {
+        let mut result = Ok(());
+        for (track, audio) in self.tracks.iter_mut().zip(&renditions.audios) {
+            let flushed = track.mpeg_ts.flush();
+            audio.hls.end(self.generation, last_duration_ms);
+            result = result.and(flushed);
+        }
+        result
+    }
+}
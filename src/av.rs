@@ -0,0 +1,7 @@
+use ffmpeg_sys::av_register_all;
+
+// Registers the muxers, demuxers and codecs of libavformat and libavcodec.
+// It must run before any of them is looked up and may run more than once.
+pub fn register_all() {
+    unsafe { av_register_all() };
+}
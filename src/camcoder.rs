@@ -1,369 +1,309 @@
-use hls::Hls;
-use std::sync::{Arc, RwLock};
-use std::ffi::CString;
-use magick_rust::{DrawingWand, MagickWand, PixelWand};
-use magick_rust::bindings::{ColorspaceType, DrawRectangle, GravityType, MagickBooleanType,
-                            MagickDrawImage, MagickExportImagePixels, StorageType};
+use renditions::Renditions;
+use std::sync::Arc;
 use chrono::prelude::*;
-use libc;
-use ffmpeg_sys::{sws_scale, AVPixelFormat, SwsContext, sws_getContext, SWS_FAST_BILINEAR};
-use std::ptr::{null, null_mut};
-use std::os::raw::{c_int, c_void};
-use openh264_sys::*;
-use std::slice::from_raw_parts;
 use mpegts::MpegTs;
 use lazybytes::LazyBytes;
-use bytes::Bytes;
+use audio::AudioTracks;
+use config::{EncoderConfig, OverlayConfig};
+use metrics::Metrics;
+use std::time::Instant;
+use error::MediaError;
+use encoder::{Encoder, EncoderParams, FrameType, Picture};
+use scaler::Scaler;
+use source::{Frame, FrameSource};
+use h264;
+
+// Requests to the camcoder thread, applied before the next frame
+pub enum Command {
+    Reconfigure(EncoderConfig, OverlayConfig),
+    SetBitrate(u64),
+    SetMaxFrameRate(f32),
+    ForceIntraFrame,
+}
+
+// What the supervisor drives at every frame tick: the camcoder, or the
+// remuxer of pre-encoded video.
+pub trait Pipeline {
+    // Produces the next frame. Returns false once the source has ended.
+    fn run(&mut self) -> Result<bool, MediaError>;
+
+    fn handle(&mut self, command: Command) -> Result<(), MediaError>;
+
+    // Flushes the segments in progress and ends every playlist. Called once
+    // when the server shuts down or the source has ended.
+    fn finish(&mut self) -> Result<(), MediaError>;
+}
+
+fn encoder_params(width: usize, height: usize, encoder: &EncoderConfig) -> EncoderParams {
+    EncoderParams {
+        width,
+        height,
+        bitrate: encoder.bitrate,
+        max_frame_rate: encoder.frame_rate(),
+        profile: encoder.profile.openh264_profile(),
+        slice_count: encoder.slice_count,
+        thread_count: encoder.thread_count as u16,
+    }
+}
 
 pub struct Camcorder {
-    hls: Arc<RwLock<Hls>>,
-    magick_wand: MagickWand,
-    text_drawing: DrawingWand,
-    background_drawing: DrawingWand,
-    magick_image_pixels: Vec<u8>,
-    y_pixels: Vec<u8>,
-    u_pixels: Vec<u8>,
-    v_pixels: Vec<u8>,
-    y_stride: usize,
-    u_stride: usize,
-    v_stride: usize,
+    renditions: Arc<Renditions>,
+    metrics: Arc<Metrics>,
+    source: Box<FrameSource>,
+    // RGB frames are converted into picture
+    picture: Picture,
     width: usize,
     height: usize,
-    sws_context: *mut SwsContext,
-    svc_encoder: *mut *const ISVCEncoderVtbl,
+    scaler: Option<Scaler>,
+    svc_encoder: Encoder,
+    encoder: EncoderConfig,
+    // Settings that need a new encoder are applied at the next segment
+    restart_encoder: bool,
+    intra_frame_requested: bool,
     frame_duration_ms: u64,
     current_ms: u64,
     ts_duration_ms: u64,
+    // Of the playlists, see Hls::start_generation
+    generation: u64,
     mpeg_ts: MpegTs,
-    h264: Vec<u8>,
+    audio_tracks: AudioTracks,
 }
 
 impl Camcorder {
-    pub fn new(hls: Arc<RwLock<Hls>>, frame_duration_ms: u64, ts_duration_ms: u64) -> Camcorder {
-        let width = 480;
-        let height = 320;
-        let mut text_fill_color = PixelWand::new();
-        text_fill_color
-            .set_color("white")
-            .expect("Failed to set text fill color");
-        let mut text_drawing = DrawingWand::new();
-        text_drawing.set_font_size(64.0);
-        text_drawing.set_gravity(GravityType::CenterGravity);
-        text_drawing.set_fill_color(&text_fill_color);
-        let mut background_color = PixelWand::new();
-        background_color
-            .set_color("black")
-            .expect("Failed to set background color");
-        let mut background_drawing = DrawingWand::new();
-        background_drawing.set_gravity(GravityType::CenterGravity);
-        background_drawing.set_fill_color(&background_color);
-        unsafe {
-            DrawRectangle(
-                background_drawing.wand,
-                0.0,
-                0.0,
-                width as f64,
-                height as f64,
-            )
-        };
-        let mut magick_wand = MagickWand::new();
-        magick_wand
-            .new_image(width, height, &background_color)
-            .expect("Failed to create new image");
-        magick_wand
-            .set_image_colorspace(ColorspaceType::sRGBColorspace)
-            .expect("Failed to set colorspace");
-        magick_wand
-            .set_image_depth(8)
-            .expect("Failed to set bit depth");
-        let mut magick_image_pixels = Vec::new();
-        magick_image_pixels.resize(width * height * 3, 0);
-
-        let y_stride = (width + 15) / 16 * 16;
-        let mut y_pixels = Vec::new();
-        y_pixels.resize(y_stride * height, 0);
-
-        let u_stride = (width + 15) / 16 * 8;
-        let mut u_pixels = Vec::new();
-        u_pixels.resize(u_stride * height, 0);
-
-        let v_stride = (width + 15) / 16 * 8;
-        let mut v_pixels = Vec::new();
-        v_pixels.resize(v_stride * height, 0);
-
-        let sws_context = unsafe {
-            sws_getContext(
-                width as i32,
-                height as i32,
-                AVPixelFormat::AV_PIX_FMT_RGB24,
-                width as i32,
-                height as i32,
-                AVPixelFormat::AV_PIX_FMT_YUV420P,
-                SWS_FAST_BILINEAR,
-                null_mut(),
-                null_mut(),
-                null_mut(),
-            )
-        };
-        if sws_context.is_null() {
-            panic!("Oops! sws context is null")
+    pub fn new(
+        renditions: Arc<Renditions>,
+        metrics: Arc<Metrics>,
+        source: Box<FrameSource>,
+        encoder: &EncoderConfig,
+        ts_duration_ms: u64,
+        generation: u64,
+        discontinuity: bool,
+    ) -> Result<Camcorder, MediaError> {
+        let frame_duration_ms = encoder.frame_duration_ms;
+        let width = renditions.video.width;
+        let height = renditions.video.height;
+        if source.size() != (width, height) {
+            let (source_width, source_height) = source.size();
+            return Err(MediaError::InvalidInput(format!(
+                "frame source of {}x{} for a {}x{} stream",
+                source_width, source_height, width, height
+            )));
         }
 
-        let mut svc_encoder = null_mut();
-        let mut r = unsafe { WelsCreateSVCEncoder(&mut svc_encoder) };
-        if r != 0 {
-            panic!("WelsCreateSVCEncoder: {}", r);
-        }
-        if svc_encoder.is_null() {
-            panic!("svc encoder is null");
-        }
+        // Segments are published only once everything is set up, so that a
+        // failed start leaves no segment that never completes.
+        let audio_tracks = AudioTracks::new(&renditions, generation)?;
 
-        let mut param = SEncParamExt::default();
-        r = unsafe { (**svc_encoder).GetDefaultParams.unwrap()(svc_encoder, &mut param) };
-        if r != 0 {
-            panic!("GetDefaultParams: {}", r);
-        }
+        let lazy_bytes = LazyBytes::new();
+        let video_stream = Some((width, height, encoder.profile.profile_level()));
+        let mpeg_ts = MpegTs::new(video_stream, None, lazy_bytes.clone())?;
 
-        let fps = 1000.0 / frame_duration_ms as f32;
-        let bitrate = 4000000;
-        param.iUsageType = CAMERA_VIDEO_REAL_TIME;
-        param.fMaxFrameRate = fps;
-        param.iMaxBitrate = UNSPECIFIED_BIT_RATE as i32;
-        param.iSpatialLayerNum = 1; // layer number at spatial level
-        param.bSimulcastAVC = true;
-        param.iMultipleThreadIdc = 4;
-        param.sSpatialLayers[0].uiProfileIdc = PRO_BASELINE;
-        param.sSpatialLayers[0].iVideoWidth = width as i32;
-        param.sSpatialLayers[0].iVideoHeight = height as i32;
-        param.sSpatialLayers[0].fFrameRate = fps;
-        param.sSpatialLayers[0].iSpatialBitrate = bitrate;
-        param.sSpatialLayers[0].iMaxSpatialBitrate = UNSPECIFIED_BIT_RATE as i32;
-        param.sSpatialLayers[0].sSliceArgument.uiSliceMode = SM_FIXEDSLCNUM_SLICE;
-        param.sSpatialLayers[0].sSliceArgument.uiSliceNum = 4;
-        param.iPicWidth = width as i32;
-        param.iPicHeight = height as i32;
-        param.iTargetBitrate = bitrate;
+        let svc_encoder = Encoder::new(&encoder_params(width, height, encoder))?;
 
-        r = unsafe { (**svc_encoder).InitializeExt.unwrap()(svc_encoder, &mut param) };
-        if r != 0 {
-            panic!("InitializeExt: {}", r);
-        }
+        // Segments are announced before they are written, every one of them
+        // lasts ts_duration_ms
+        audio_tracks.publish(&renditions, ts_duration_ms, discontinuity);
+        renditions
+            .video
+            .hls
+            .add_new_segment(generation, ts_duration_ms, lazy_bytes, discontinuity);
 
-        let mut video_format = videoFormatI420 as c_int;
-        r = unsafe {
-            (**svc_encoder).SetOption.unwrap()(
-                svc_encoder,
-                ENCODER_OPTION_DATAFORMAT,
-                &mut video_format as *mut c_int as *mut c_void,
-            )
-        };
-        if r != 0 {
-            panic!("SetOption: {}", r);
-        }
-
-        let mut rc_frame_skip = 0 as c_int;
-        r = unsafe {
-            (**svc_encoder).SetOption.unwrap()(
-                svc_encoder,
-                ENCODER_OPTION_RC_FRAME_SKIP,
-                &mut rc_frame_skip as *mut c_int as *mut c_void,
-            )
-        };
-        if r != 0 {
-            panic!("SetOption: {}", r);
-        }
-
-        let lazy_bytes = Arc::new(RwLock::new(LazyBytes {
-            bytes: Bytes::new(),
-            completion: false,
-        }));
-        {
-            let mut h = hls.write().expect("Failed to lock hls segments");
-            h.add_new_segment(ts_duration_ms, lazy_bytes.clone());
-        }
-
-        Camcorder {
-            magick_wand,
-            text_drawing,
-            background_drawing,
-            magick_image_pixels,
-            hls,
+        Ok(Camcorder {
+            source,
+            renditions,
+            metrics,
             width,
             height,
-            y_pixels,
-            u_pixels,
-            v_pixels,
-            y_stride,
-            u_stride,
-            v_stride,
-            sws_context,
+            picture: Picture::new(width, height),
+            scaler: None,
             svc_encoder,
+            encoder: encoder.clone(),
+            restart_encoder: false,
+            intra_frame_requested: false,
             frame_duration_ms,
             current_ms: 0,
             ts_duration_ms,
-            mpeg_ts: unsafe { MpegTs::new(width, height, lazy_bytes) },
-            h264: Vec::new(),
+            generation,
+            mpeg_ts,
+            audio_tracks,
+        })
+    }
+
+    fn reconfigure(
+        &mut self,
+        encoder: &EncoderConfig,
+        overlay: &OverlayConfig,
+    ) -> Result<(), MediaError> {
+        self.source.set_overlay(overlay);
+        if self.encoder.bitrate != encoder.bitrate {
+            self.set_bitrate(encoder.bitrate)?;
+        }
+        if self.encoder.max_frame_rate != encoder.max_frame_rate {
+            let max_frame_rate = encoder
+                .max_frame_rate
+                .unwrap_or(1000.0 / self.frame_duration_ms as f32);
+            self.set_max_frame_rate(max_frame_rate)?;
+            self.encoder.max_frame_rate = encoder.max_frame_rate;
+            self.publish_encoder();
+        }
+        if self.encoder.profile != encoder.profile || self.encoder.slice_count != encoder.slice_count
+            || self.encoder.thread_count != encoder.thread_count
+        {
+            self.encoder.profile = encoder.profile;
+            self.encoder.slice_count = encoder.slice_count;
+            self.encoder.thread_count = encoder.thread_count;
+            self.restart_encoder = true;
+            info!(
+                "Restarting encoder at the next segment with discontinuity: \
+                 encoder.profile = {:?}, encoder.slice_count = {}, encoder.thread_count = {}",
+                encoder.profile, encoder.slice_count, encoder.thread_count
+            );
         }
+        Ok(())
+    }
+
+    fn set_bitrate(&mut self, bitrate: u64) -> Result<(), MediaError> {
+        self.svc_encoder.set_bitrate(bitrate)?;
+        self.encoder.bitrate = bitrate;
+        self.publish_encoder();
+        info!("Applied encoder.bitrate = {}", bitrate);
+        Ok(())
+    }
+
+    fn set_max_frame_rate(&mut self, max_frame_rate: f32) -> Result<(), MediaError> {
+        self.svc_encoder.set_max_frame_rate(max_frame_rate)?;
+        self.encoder.max_frame_rate = Some(max_frame_rate);
+        self.publish_encoder();
+        info!("Applied encoder.max_frame_rate = {}", max_frame_rate);
+        Ok(())
     }
 
-    pub fn run(&mut self) -> bool {
+    fn publish_encoder(&self) {
+        self.renditions.publish_encoder(self.encoder.clone());
+    }
+}
+
+impl Pipeline for Camcorder {
+    // Encodes one frame
+    fn run(&mut self) -> Result<bool, MediaError> {
         let force_intra_frame = if self.current_ms % self.ts_duration_ms == 0 {
             true
         } else {
-            false
+            self.intra_frame_requested
         };
-        self.current_ms += self.frame_duration_ms;
         let now = Local::now();
-        let text = now.format("%Y-%m-%d\n%H:%M:%S\n%f").to_string();
-        if unsafe { MagickDrawImage(self.magick_wand.wand, self.background_drawing.wand) }
-            == MagickBooleanType::MagickFalse
-        {
-            panic!("Failed to draw background image");
-        };
-        self.magick_wand
-            .annotate_image(&self.text_drawing, 0.0, 0.0, 0.0, &text)
-            .expect("Failed to write text to image");
-        let rgb = CString::new("RGB")
-            .expect("Oops! invalid CString?")
-            .into_bytes_with_nul();
-        if unsafe {
-            MagickExportImagePixels(
-                self.magick_wand.wand,
-                0,
-                0,
-                self.width,
-                self.height,
-                rgb.as_ptr() as *const i8,
-                StorageType::CharPixel,
-                self.magick_image_pixels.as_mut_ptr() as *mut libc::c_void,
-            )
-        } == MagickBooleanType::MagickFalse
-        {
-            panic!("Failed to get image pixels");
-        }
-
-        let src: [*const u8; 4] = [self.magick_image_pixels.as_ptr(), null(), null(), null()];
-        let src_strides: [c_int; 4] = [self.width as i32 * 3, 0, 0, 0];
-        let dst: [*const u8; 4] = [
-            self.y_pixels.as_ptr(),
-            self.u_pixels.as_ptr(),
-            self.v_pixels.as_ptr(),
-            null(),
-        ];
-        let dst_strides: [c_int; 4] = [
-            self.y_stride as i32,
-            self.u_stride as i32,
-            self.v_stride as i32,
-            0,
-        ];
-        if unsafe {
-            sws_scale(
-                self.sws_context,
-                src.as_ptr(),
-                src_strides.as_ptr(),
-                0,
-                self.height as i32,
-                dst.as_ptr(),
-                dst_strides.as_ptr(),
-            )
-        } == 0
-        {
-            panic!("Failed to execute sws_scale");
-        }
-
-        let mut info = SFrameBSInfo::default();
-        let mut pic = SSourcePicture::default();
-        pic.uiTimeStamp = self.current_ms as i64;
-        pic.iPicWidth = self.width as i32;
-        pic.iPicHeight = self.height as i32;
-        pic.iColorFormat = videoFormatI420 as i32;
-        pic.iStride[0] = self.y_stride as i32;
-        pic.iStride[1] = self.u_stride as i32;
-        pic.iStride[2] = self.v_stride as i32;
-        pic.pData[0] = self.y_pixels.as_mut_ptr();
-        pic.pData[1] = self.u_pixels.as_mut_ptr();
-        pic.pData[2] = self.v_pixels.as_mut_ptr();
+        // The frame borrows the source until it is encoded
+        let mut encoded = {
+            let render_started_at = Instant::now();
+            let frame = match self.source.frame(self.current_ms)? {
+                Some(frame) => frame,
+                None => return Ok(false),
+            };
+            self.metrics
+                .frame_render_seconds
+                .observe_duration(render_started_at.elapsed());
+            let picture = match frame {
+                Frame::Rgb {
+                    timestamp_ms,
+                    data,
+                    stride,
+                } => {
+                    if self.scaler.is_none() {
+                        self.scaler = Some(Scaler::rgb24_to_i420(self.width, self.height)?);
+                    }
+                    let swscale_started_at = Instant::now();
+                    if let Some(ref mut scaler) = self.scaler {
+                        scaler.scale(data, stride, &mut self.picture)?;
+                    }
+                    self.metrics
+                        .swscale_seconds
+                        .observe_duration(swscale_started_at.elapsed());
+                    self.picture.timestamp_ms = timestamp_ms;
+                    &self.picture
+                }
+                Frame::I420(picture) => picture,
+            };
 
-        if force_intra_frame {
-            let r =
-                unsafe { (**self.svc_encoder).ForceIntraFrame.unwrap()(self.svc_encoder, true) };
-            if r != 0 {
-                panic!("ForceIntraFrame: {}", r);
+            if force_intra_frame {
+                self.svc_encoder.force_intra_frame()?;
             }
-        }
 
-        let r = unsafe {
-            (**self.svc_encoder).EncodeFrame.unwrap()(self.svc_encoder, &mut pic, &mut info)
+            let encode_started_at = Instant::now();
+            let encoded = self.svc_encoder.encode(picture)?;
+            self.metrics
+                .encode_seconds
+                .observe_duration(encode_started_at.elapsed());
+            encoded
         };
-        if r != 0 {
-            if r != 0 {
-                panic!("EncodeFrame: {}", r);
-            }
-        }
-
-        if info.eFrameType == videoFrameTypeSkip {
-            eprintln!("skip frame")
-        } else if info.eFrameType == videoFrameTypeInvalid {
-            eprintln!("inval")
-        } else if info.eFrameType == videoFrameTypeIDR || info.eFrameType == videoFrameTypeI
-            || info.eFrameType == videoFrameTypeP
-            || info.eFrameType == videoFrameTypeIPMixed
-        {
-        } else {
-            eprintln!("unknown frame: {:?}", info.eFrameType)
-        }
-
-        for spatial_id in 0..1 {
-            for layer in 0..info.iLayerNum {
-                if info.sLayerInfo[layer as usize].uiSpatialId != spatial_id {
-                    continue;
-                }
+        self.intra_frame_requested = false;
+        self.current_ms += self.frame_duration_ms;
 
-                let mut size = 0;
-                for i in 0..info.sLayerInfo[layer as usize].iNalCount {
-                    size += unsafe {
-                        *info.sLayerInfo[layer as usize]
-                            .pNalLengthInByte
-                            .offset(i as isize)
-                    };
-                }
-                if size > 0 {
-                    unsafe {
-                        self.h264.extend_from_slice(from_raw_parts(
-                            info.sLayerInfo[layer as usize].pBsBuf,
-                            size as usize,
-                        ))
-                    };
+        match encoded.frame_type {
+            FrameType::Skip => {
+                self.metrics.skipped_frames.inc();
+                warn!("skip frame")
+            }
+            FrameType::Invalid => {
+                self.metrics.invalid_frames.inc();
+                warn!("invalid frame")
+            }
+            FrameType::Unknown(frame_type) => warn!("unknown frame: {:?}", frame_type),
+            frame_type => {
+                self.metrics.encoded_frames.inc();
+                if let Some(label) = frame_type.label() {
+                    self.metrics.frame_bytes(label).observe(encoded.data.len());
                 }
             }
         }
 
-        unsafe {
-            self.mpeg_ts.write(
-                &mut self.h264,
-                self.current_ms - self.frame_duration_ms,
-                self.frame_duration_ms,
-                force_intra_frame,
-            )
-        };
-        self.h264.clear();
+        let start_ms = self.current_ms - self.frame_duration_ms;
+        let key = h264::is_idr(&encoded.data);
+        self.mpeg_ts.write(
+            &mut encoded.data,
+            start_ms,
+            start_ms,
+            self.frame_duration_ms,
+            key,
+        )?;
+
+        let wall_clock_ms = (now.nanosecond() / 1_000_000) as u64;
+        self.audio_tracks.write(start_ms, self.current_ms, wall_clock_ms)?;
 
         if self.current_ms % self.ts_duration_ms != 0 {
-            return true;
+            return Ok(true);
         }
 
-        unsafe { self.mpeg_ts.flush() };
-
-        let lazy_bytes = Arc::new(RwLock::new(LazyBytes {
-            bytes: Bytes::new(),
-            completion: false,
-        }));
-        {
-            let mut hls = self.hls.write().expect("Failed to lock hls segments");
-            hls.add_new_segment(self.ts_duration_ms, lazy_bytes.clone());
+        // Cut every rendition at the same tick so that segment boundaries are aligned
+        self.mpeg_ts.flush()?;
+        self.metrics.observe_segment(
+            &self.renditions.video.name,
+            self.mpeg_ts.written_bytes(),
+            self.ts_duration_ms,
+        );
+        let discontinuity = self.restart_encoder;
+        if self.restart_encoder {
+            self.svc_encoder =
+                Encoder::new(&encoder_params(self.width, self.height, &self.encoder))?;
+            self.restart_encoder = false;
+            self.publish_encoder();
         }
+        let lazy_bytes = LazyBytes::new();
+        self.renditions.video.hls.add_new_segment(
+            self.generation,
+            self.ts_duration_ms,
+            lazy_bytes.clone(),
+            discontinuity,
+        );
+        self.mpeg_ts = MpegTs::new(
+            Some((self.width, self.height, self.encoder.profile.profile_level())),
+            None,
+            lazy_bytes.clone(),
+        )?;
 
-        self.mpeg_ts = unsafe { MpegTs::new(self.width, self.height, lazy_bytes.clone()) };
+        self.audio_tracks
+            .cut(&self.renditions, &self.metrics, self.ts_duration_ms)?;
+        self.audio_tracks
+            .publish(&self.renditions, self.ts_duration_ms, false);
         /*
         let mut file = OpenOptions::new()
             .create(true)
@@ -372,6 +312,35 @@ impl Camcorder {
             .expect("open!");
         file.write_all(&self.h264).expect("write!");
         */
-        return true;
+        Ok(true)
+    }
+
+    // Runs a command sent from the server thread. Bitrate, frame rate,
+    // keyframe requests and the overlay take effect from the next frame, the
+    // rest restarts the encoder at the next segment.
+    fn handle(&mut self, command: Command) -> Result<(), MediaError> {
+        match command {
+            Command::Reconfigure(encoder, overlay) => self.reconfigure(&encoder, &overlay),
+            Command::SetBitrate(bitrate) => self.set_bitrate(bitrate),
+            Command::SetMaxFrameRate(max_frame_rate) => self.set_max_frame_rate(max_frame_rate),
+            Command::ForceIntraFrame => {
+                self.intra_frame_requested = true;
+                info!("Forcing an IDR frame at the next tick");
+                Ok(())
+            }
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), MediaError> {
+        let last_duration_ms = self.current_ms % self.ts_duration_ms;
+        let result = self.mpeg_ts.flush();
+        self.renditions
+            .video
+            .hls
+            .end(self.generation, Some(last_duration_ms));
+        result.and(
+            self.audio_tracks
+                .finish(&self.renditions, Some(last_duration_ms)),
+        )
     }
 }
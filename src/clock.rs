@@ -0,0 +1,189 @@
+use chrono::prelude::*;
+use config::OverlayConfig;
+use error::MediaError;
+use libc;
+use magick_rust::{DrawingWand, MagickWand, PixelWand};
+use magick_rust::bindings::{ColorspaceType, DrawRectangle, GravityType, MagickBooleanType,
+                            MagickDrawImage, MagickExportImagePixels, StorageType};
+use source::{Frame, FrameSource};
+use std::ffi::CString;
+
+// Draws the wall clock, below the overlay text if any, with ImageMagick.
+pub struct ClockSource {
+    magick_wand: MagickWand,
+    text_drawing: DrawingWand,
+    background_drawing: DrawingWand,
+    pixels: Vec<u8>,
+    width: usize,
+    height: usize,
+    overlay_text: String,
+}
+
+impl ClockSource {
+    pub fn new(
+        width: usize,
+        height: usize,
+        overlay: &OverlayConfig,
+    ) -> Result<ClockSource, MediaError> {
+        let mut text_fill_color = PixelWand::new();
+        text_fill_color
+            .set_color("white")
+            .map_err(|err| MediaError::Magick("PixelSetColor", err))?;
+        let mut text_drawing = DrawingWand::new();
+        text_drawing.set_font_size(64.0);
+        text_drawing.set_gravity(GravityType::CenterGravity);
+        text_drawing.set_fill_color(&text_fill_color);
+        let mut background_color = PixelWand::new();
+        background_color
+            .set_color("black")
+            .map_err(|err| MediaError::Magick("PixelSetColor", err))?;
+        let mut background_drawing = DrawingWand::new();
+        background_drawing.set_gravity(GravityType::CenterGravity);
+        background_drawing.set_fill_color(&background_color);
+        unsafe {
+            DrawRectangle(
+                background_drawing.wand,
+                0.0,
+                0.0,
+                width as f64,
+                height as f64,
+            )
+        };
+        let mut magick_wand = MagickWand::new();
+        magick_wand
+            .new_image(width, height, &background_color)
+            .map_err(|err| MediaError::Magick("MagickNewImage", err))?;
+        magick_wand
+            .set_image_colorspace(ColorspaceType::sRGBColorspace)
+            .map_err(|err| MediaError::Magick("MagickSetImageColorspace", err))?;
+        magick_wand
+            .set_image_depth(8)
+            .map_err(|err| MediaError::Magick("MagickSetImageDepth", err))?;
+        Ok(ClockSource {
+            magick_wand,
+            text_drawing,
+            background_drawing,
+            pixels: vec![0; width * height * 3],
+            width,
+            height,
+            overlay_text: overlay.text.clone(),
+        })
+    }
+}
+
+impl FrameSource for ClockSource {
+    fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    fn frame(&mut self, timestamp_ms: u64) -> Result<Option<Frame>, MediaError> {
+        let mut text = Local::now().format("%Y-%m-%d\n%H:%M:%S\n%f").to_string();
+        if !self.overlay_text.is_empty() {
+            text = format!("{}\n{}", self.overlay_text, text);
+        }
+        if unsafe { MagickDrawImage(self.magick_wand.wand, self.background_drawing.wand) }
+            == MagickBooleanType::MagickFalse
+        {
+            return Err(MediaError::Failed("MagickDrawImage"));
+        };
+        self.magick_wand
+            .annotate_image(&self.text_drawing, 0.0, 0.0, 0.0, &text)
+            .map_err(|err| MediaError::Magick("MagickAnnotateImage", err))?;
+        let rgb = CString::new("RGB")
+            .expect("Oops! invalid CString?")
+            .into_bytes_with_nul();
+        if unsafe {
+            MagickExportImagePixels(
+                self.magick_wand.wand,
+                0,
+                0,
+                self.width,
+                self.height,
+                rgb.as_ptr() as *const i8,
+                StorageType::CharPixel,
+                self.pixels.as_mut_ptr() as *mut libc::c_void,
+            )
+        } == MagickBooleanType::MagickFalse
+        {
+            return Err(MediaError::Failed("MagickExportImagePixels"));
+        }
+        Ok(Some(Frame::Rgb {
+            timestamp_ms,
+            data: &self.pixels,
+            stride: self.width * 3,
+        }))
+    }
+
+    fn set_overlay(&mut self, overlay: &OverlayConfig) {
+        if self.overlay_text != overlay.text {
+            self.overlay_text = overlay.text.clone();
+            info!("Applied overlay.text = {:?}", self.overlay_text);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use magick_rust::magick_wand_genesis;
+
+    const WIDTH: usize = 320;
+    const HEIGHT: usize = 180;
+
+    fn clock(text: &str) -> ClockSource {
+        magick_wand_genesis();
+        let overlay = OverlayConfig {
+            text: text.to_owned(),
+        };
+        ClockSource::new(WIDTH, HEIGHT, &overlay).unwrap()
+    }
+
+    // Returns the timestamp, pixels and stride of the frame at timestamp_ms
+    fn rgb_frame(source: &mut ClockSource, timestamp_ms: u64) -> (u64, Vec<u8>, usize) {
+        match source.frame(timestamp_ms).unwrap() {
+            Some(Frame::Rgb {
+                timestamp_ms,
+                data,
+                stride,
+            }) => (timestamp_ms, data.to_vec(), stride),
+            _ => panic!("clock produced no RGB frame"),
+        }
+    }
+
+    #[test]
+    fn produces_packed_rgb_frames_of_the_size() {
+        let mut source = clock("");
+        assert_eq!(source.size(), (WIDTH, HEIGHT));
+        let (timestamp_ms, data, stride) = rgb_frame(&mut source, 1000);
+        assert_eq!(timestamp_ms, 1000);
+        assert_eq!(stride, WIDTH * 3);
+        assert_eq!(data.len(), WIDTH * HEIGHT * 3);
+    }
+
+    #[test]
+    fn draws_white_text_on_black() {
+        let mut source = clock("");
+        let (_, data, _) = rgb_frame(&mut source, 0);
+        assert_eq!(&data[..3], &[0, 0, 0]);
+        assert!(data.iter().any(|&value| value == 255));
+    }
+
+    #[test]
+    fn keeps_producing_frames() {
+        let mut source = clock("");
+        for frame in 0..3 {
+            let (timestamp_ms, _, _) = rgb_frame(&mut source, frame * 33);
+            assert_eq!(timestamp_ms, frame * 33);
+        }
+    }
+
+    #[test]
+    fn applies_overlay_text() {
+        let mut source = clock("");
+        source.set_overlay(&OverlayConfig {
+            text: "camera 1".to_owned(),
+        });
+        assert_eq!(source.overlay_text, "camera 1");
+        rgb_frame(&mut source, 0);
+    }
+}
@@ -0,0 +1,136 @@
+use brotli;
+use bytes::Bytes;
+use flate2;
+use flate2::write::GzEncoder;
+use hyper::header::{q, AcceptEncoding, Encoding};
+use hyper::server::Request;
+use std::io::Write;
+
+const BROTLI_BUFFER_SIZE: usize = 4096;
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW_BITS: u32 = 22;
+
+// Content codings we can produce. Only text responses (playlists and JSON) are
+// compressed; media segments are already compressed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ContentCoding {
+    Identity,
+    Gzip,
+    Brotli,
+}
+
+impl ContentCoding {
+    pub fn negotiate(req: &Request) -> ContentCoding {
+        let accept_encoding = match req.headers().get::<AcceptEncoding>() {
+            Some(accept_encoding) => accept_encoding,
+            None => return ContentCoding::Identity,
+        };
+        let quality = |encoding: Encoding| {
+            accept_encoding
+                .iter()
+                .filter(|item| item.item == encoding)
+                .map(|item| item.quality)
+                .max()
+                .unwrap_or(q(0))
+        };
+        // The highest quality wins; brotli is preferred only on a tie.
+        let brotli = quality(Encoding::Brotli);
+        let gzip = quality(Encoding::Gzip);
+        if brotli > q(0) && brotli >= gzip {
+            ContentCoding::Brotli
+        } else if gzip > q(0) {
+            ContentCoding::Gzip
+        } else {
+            ContentCoding::Identity
+        }
+    }
+
+    pub fn token(&self) -> &'static str {
+        match *self {
+            ContentCoding::Identity => "identity",
+            ContentCoding::Gzip => "gzip",
+            ContentCoding::Brotli => "br",
+        }
+    }
+
+    pub fn encoding(&self) -> Option<Encoding> {
+        match *self {
+            ContentCoding::Identity => None,
+            ContentCoding::Gzip => Some(Encoding::Gzip),
+            ContentCoding::Brotli => Some(Encoding::Brotli),
+        }
+    }
+
+    pub fn encode(&self, bytes: &[u8]) -> Bytes {
+        match *self {
+            ContentCoding::Identity => Bytes::from(bytes),
+            ContentCoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder
+                    .write_all(bytes)
+                    .expect("Failed to write gzip encoder");
+                Bytes::from(encoder.finish().expect("Failed to finish gzip encoder"))
+            }
+            ContentCoding::Brotli => {
+                let mut encoded = Vec::new();
+                {
+                    let mut encoder = brotli::CompressorWriter::new(
+                        &mut encoded,
+                        BROTLI_BUFFER_SIZE,
+                        BROTLI_QUALITY,
+                        BROTLI_WINDOW_BITS,
+                    );
+                    encoder
+                        .write_all(bytes)
+                        .expect("Failed to write brotli encoder");
+                }
+                Bytes::from(encoded)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::{qitem, QualityItem};
+    use hyper::Method;
+
+    fn negotiate(items: Vec<QualityItem<Encoding>>) -> ContentCoding {
+        let mut req = Request::new(Method::Get, "/".parse().unwrap());
+        req.headers_mut().set(AcceptEncoding(items));
+        ContentCoding::negotiate(&req)
+    }
+
+    #[test]
+    fn prefers_brotli_on_a_tie() {
+        let items = vec![qitem(Encoding::Gzip), qitem(Encoding::Brotli)];
+        assert_eq!(negotiate(items), ContentCoding::Brotli);
+    }
+
+    #[test]
+    fn picks_the_highest_quality() {
+        let items = vec![
+            QualityItem::new(Encoding::Brotli, q(500)),
+            qitem(Encoding::Gzip),
+        ];
+        assert_eq!(negotiate(items), ContentCoding::Gzip);
+    }
+
+    #[test]
+    fn skips_refused_codings() {
+        let items = vec![QualityItem::new(Encoding::Brotli, q(0))];
+        assert_eq!(negotiate(items), ContentCoding::Identity);
+        let items = vec![
+            QualityItem::new(Encoding::Brotli, q(0)),
+            QualityItem::new(Encoding::Gzip, q(100)),
+        ];
+        assert_eq!(negotiate(items), ContentCoding::Gzip);
+    }
+
+    #[test]
+    fn identity_without_header() {
+        let req = Request::new(Method::Get, "/".parse().unwrap());
+        assert_eq!(ContentCoding::negotiate(&req), ContentCoding::Identity);
+    }
+}
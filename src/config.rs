@@ -0,0 +1,688 @@
+use clap::{App, Arg, ArgMatches};
+use cors::{AllowedOrigins, CorsPolicy};
+use h264::ProfileLevel;
+use log::LevelFilter;
+use openh264_sys::{EProfileIdc, PRO_BASELINE, PRO_HIGH, PRO_MAIN};
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use toml;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, ::std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Argument(String, String),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Read(ref path, ref err) => {
+                write!(f, "Failed to read config file {}: {}", path.display(), err)
+            }
+            ConfigError::Parse(ref path, ref err) => {
+                write!(f, "Failed to parse config file {}: {}", path.display(), err)
+            }
+            ConfigError::Argument(ref name, ref value) => {
+                write!(f, "Invalid value {:?} for --{}", value, name)
+            }
+            ConfigError::Invalid(ref message) => write!(f, "Invalid configuration: {}", message),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn description(&self) -> &str {
+        "configuration error"
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    Baseline,
+    Main,
+    High,
+}
+
+impl Profile {
+    pub fn openh264_profile(&self) -> EProfileIdc {
+        match *self {
+            Profile::Baseline => PRO_BASELINE,
+            Profile::Main => PRO_MAIN,
+            Profile::High => PRO_HIGH,
+        }
+    }
+
+    // What the encoder writes into the SPS, level 4.1
+    pub fn profile_level(&self) -> ProfileLevel {
+        let (profile_idc, constraint_flags) = match *self {
+            Profile::Baseline => (66, 0xc0),
+            Profile::Main => (77, 0x40),
+            Profile::High => (100, 0x00),
+        };
+        ProfileLevel {
+            profile_idc,
+            constraint_flags,
+            level_idc: 41,
+        }
+    }
+}
+
+impl FromStr for Profile {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Profile, ()> {
+        match s {
+            "baseline" => Ok(Profile::Baseline),
+            "main" => Ok(Profile::Main),
+            "high" => Ok(Profile::High),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<LogFormat, ()> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceKind {
+    Clock,
+    Y4m,
+    Raw,
+    // A media file decoded with libavformat and libavcodec
+    File,
+    // H.264 from a file or an ingest URL, segmented without re-encoding
+    Passthrough,
+}
+
+impl FromStr for SourceKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<SourceKind, ()> {
+        match s {
+            "clock" => Ok(SourceKind::Clock),
+            "y4m" => Ok(SourceKind::Y4m),
+            "raw" => Ok(SourceKind::Raw),
+            "file" => Ok(SourceKind::File),
+            "passthrough" => Ok(SourceKind::Passthrough),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    // error, warn, info, debug or trace
+    pub level: String,
+    pub format: LogFormat,
+}
+
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig {
+            level: "info".to_owned(),
+            format: LogFormat::Text,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: String,
+    // How long in-flight downloads may take to finish on shutdown
+    pub shutdown_timeout_ms: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            bind: "0.0.0.0:3001".to_owned(),
+            shutdown_timeout_ms: 5000,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncoderConfig {
+    pub width: usize,
+    pub height: usize,
+    pub bitrate: u64,
+    pub profile: Profile,
+    pub frame_duration_ms: u64,
+    // Rate control frame rate, defaults to 1000 / frame_duration_ms
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_frame_rate: Option<f32>,
+    pub slice_count: u32,
+    pub thread_count: u32,
+}
+
+impl EncoderConfig {
+    pub fn frame_rate(&self) -> f32 {
+        self.max_frame_rate
+            .unwrap_or(1000.0 / self.frame_duration_ms as f32)
+    }
+
+    pub fn validate_bitrate(bitrate: u64) -> Result<(), String> {
+        if bitrate == 0 || bitrate > i32::max_value() as u64 {
+            return Err(format!(
+                "bitrate must be between 1 and {}, got {}",
+                i32::max_value(),
+                bitrate
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn validate_max_frame_rate(&self, max_frame_rate: f32) -> Result<(), String> {
+        let limit = 1000.0 / self.frame_duration_ms as f32;
+        if !(max_frame_rate > 0.0 && max_frame_rate <= limit) {
+            return Err(format!(
+                "max_frame_rate must be greater than 0 and at most {}, got {}",
+                limit, max_frame_rate
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for EncoderConfig {
+    fn default() -> EncoderConfig {
+        EncoderConfig {
+            width: 480,
+            height: 320,
+            bitrate: 4000000,
+            profile: Profile::Baseline,
+            frame_duration_ms: 50, // 20fps
+            max_frame_rate: None,
+            slice_count: 4,
+            thread_count: 4,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SegmenterConfig {
+    pub segment_duration_ms: u64,
+}
+
+impl Default for SegmenterConfig {
+    fn default() -> SegmenterConfig {
+        SegmenterConfig {
+            segment_duration_ms: 350,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PlaylistConfig {
+    pub window_size: usize,
+    // Number of newest segments excluded when computing EXT-X-MEDIA-SEQUENCE
+    pub live_edge_segments: usize,
+}
+
+impl Default for PlaylistConfig {
+    fn default() -> PlaylistConfig {
+        PlaylistConfig {
+            window_size: 10,
+            live_edge_segments: 1,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OverlayConfig {
+    // Extra line drawn above the clock, empty for none
+    pub text: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SourceConfig {
+    pub kind: SourceKind,
+    // Input of y4m, raw, file and passthrough sources, "-" for stdin. Named
+    // pipes are read like files. Passthrough also takes URLs such as rtmp://.
+    pub path: String,
+    // Reopen the input at its end instead of ending the stream
+    #[serde(rename = "loop")]
+    pub looping: bool,
+    // Frame rate of raw I420 input, whose size is encoder.width x encoder.height
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frame_rate: Option<f32>,
+}
+
+impl Default for SourceConfig {
+    fn default() -> SourceConfig {
+        SourceConfig {
+            kind: SourceKind::Clock,
+            path: "-".to_owned(),
+            looping: false,
+            frame_rate: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    // /readyz fails when the newest segment is older than this many target durations
+    pub stale_after_target_durations: u32,
+}
+
+impl Default for HealthConfig {
+    fn default() -> HealthConfig {
+        HealthConfig {
+            stale_after_target_durations: 3,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SupervisorConfig {
+    // The camcoder is recreated when it completed no frame tick for this many
+    // segment durations after its first one
+    pub stall_after_target_durations: u32,
+    // Delay before the first restart, doubled on each consecutive failure
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> SupervisorConfig {
+        SupervisorConfig {
+            stall_after_target_durations: 3,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 30000,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    // Bearer token for /api/encoder, which is disabled while this is empty
+    pub token: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    // "*" allows any origin
+    pub allowed_origins: Vec<String>,
+    pub exposed_headers: Vec<String>,
+    pub max_age_seconds: u32,
+}
+
+impl Default for CorsConfig {
+    fn default() -> CorsConfig {
+        let policy = CorsPolicy::default();
+        CorsConfig {
+            allowed_origins: vec!["*".to_owned()],
+            exposed_headers: policy.exposed_headers,
+            max_age_seconds: policy.max_age_seconds,
+        }
+    }
+}
+
+impl CorsConfig {
+    pub fn to_policy(&self) -> CorsPolicy {
+        let allowed_origins = if self.allowed_origins.iter().any(|origin| origin == "*") {
+            AllowedOrigins::Any
+        } else {
+            AllowedOrigins::List(self.allowed_origins.clone())
+        };
+        CorsPolicy {
+            allowed_origins,
+            exposed_headers: self.exposed_headers.clone(),
+            max_age_seconds: self.max_age_seconds,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub log: LogConfig,
+    pub server: ServerConfig,
+    pub encoder: EncoderConfig,
+    pub segmenter: SegmenterConfig,
+    pub playlist: PlaylistConfig,
+    pub overlay: OverlayConfig,
+    pub source: SourceConfig,
+    pub health: HealthConfig,
+    pub supervisor: SupervisorConfig,
+    pub admin: AdminConfig,
+    pub cors: CorsConfig,
+}
+
+fn parse_arg<T: FromStr>(matches: &ArgMatches, name: &str) -> Result<Option<T>, ConfigError> {
+    match matches.value_of(name) {
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| ConfigError::Argument(name.to_owned(), value.to_owned())),
+        None => Ok(None),
+    }
+}
+
+impl Config {
+    pub fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let mut content = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut content))
+            .map_err(|err| ConfigError::Read(path.to_owned(), err))?;
+        toml::from_str(&content).map_err(|err| ConfigError::Parse(path.to_owned(), err))
+    }
+
+    // Reads the config file given by --config, then lets the other flags
+    // override individual settings. Called again on reload, so flags keep
+    // taking precedence over the edited file.
+    pub fn from_args() -> Result<Config, ConfigError> {
+        let matches = App::new("fastest-practical-cactus")
+            .about("Low latency HLS origin server")
+            .arg(
+                Arg::with_name("config")
+                    .long("config")
+                    .short("c")
+                    .value_name("FILE")
+                    .help("TOML configuration file")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("log-level")
+                    .long("log-level")
+                    .possible_values(&["error", "warn", "info", "debug", "trace"])
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("log-format")
+                    .long("log-format")
+                    .possible_values(&["text", "json"])
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("bind")
+                    .long("bind")
+                    .value_name("ADDR")
+                    .help("Address to listen on, e.g. 0.0.0.0:3001")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("width")
+                    .long("width")
+                    .value_name("PIXELS")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("height")
+                    .long("height")
+                    .value_name("PIXELS")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("bitrate")
+                    .long("bitrate")
+                    .value_name("BPS")
+                    .help("Target video bitrate in bits per second")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("profile")
+                    .long("profile")
+                    .possible_values(&["baseline", "main", "high"])
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("frame-duration-ms")
+                    .long("frame-duration-ms")
+                    .value_name("MS")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("slice-count")
+                    .long("slice-count")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("thread-count")
+                    .long("thread-count")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("segment-duration-ms")
+                    .long("segment-duration-ms")
+                    .value_name("MS")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("source")
+                    .long("source")
+                    .possible_values(&["clock", "y4m", "raw", "file", "passthrough"])
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("input")
+                    .long("input")
+                    .value_name("PATH")
+                    .help("Input of the y4m, raw, file and passthrough sources, - for stdin")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("loop")
+                    .long("loop")
+                    .help("Reopen the input at its end"),
+            )
+            .arg(
+                Arg::with_name("playlist-window")
+                    .long("playlist-window")
+                    .value_name("SEGMENTS")
+                    .takes_value(true),
+            )
+            .get_matches();
+
+        let mut config = match matches.value_of("config") {
+            Some(path) => Config::from_file(Path::new(path))?,
+            None => Config::default(),
+        };
+        if let Some(level) = parse_arg(&matches, "log-level")? {
+            config.log.level = level;
+        }
+        if let Some(format) = parse_arg(&matches, "log-format")? {
+            config.log.format = format;
+        }
+        if let Some(bind) = parse_arg(&matches, "bind")? {
+            config.server.bind = bind;
+        }
+        if let Some(width) = parse_arg(&matches, "width")? {
+            config.encoder.width = width;
+        }
+        if let Some(height) = parse_arg(&matches, "height")? {
+            config.encoder.height = height;
+        }
+        if let Some(bitrate) = parse_arg(&matches, "bitrate")? {
+            config.encoder.bitrate = bitrate;
+        }
+        if let Some(profile) = parse_arg(&matches, "profile")? {
+            config.encoder.profile = profile;
+        }
+        if let Some(frame_duration_ms) = parse_arg(&matches, "frame-duration-ms")? {
+            config.encoder.frame_duration_ms = frame_duration_ms;
+        }
+        if let Some(slice_count) = parse_arg(&matches, "slice-count")? {
+            config.encoder.slice_count = slice_count;
+        }
+        if let Some(thread_count) = parse_arg(&matches, "thread-count")? {
+            config.encoder.thread_count = thread_count;
+        }
+        if let Some(segment_duration_ms) = parse_arg(&matches, "segment-duration-ms")? {
+            config.segmenter.segment_duration_ms = segment_duration_ms;
+        }
+        if let Some(kind) = parse_arg(&matches, "source")? {
+            config.source.kind = kind;
+        }
+        if let Some(path) = parse_arg(&matches, "input")? {
+            config.source.path = path;
+        }
+        if matches.is_present("loop") {
+            config.source.looping = true;
+        }
+        if let Some(window_size) = parse_arg(&matches, "playlist-window")? {
+            config.playlist.window_size = window_size;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn bind_addr(&self) -> SocketAddr {
+        self.server
+            .bind
+            .parse()
+            .expect(&format!("Failed to parse address {}", self.server.bind))
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |message: String| Err(ConfigError::Invalid(message));
+        if self.log.level.parse::<LevelFilter>().is_err() {
+            return invalid(format!(
+                "log.level must be one of error, warn, info, debug or trace, got {:?}",
+                self.log.level
+            ));
+        }
+        if self.server.bind.parse::<SocketAddr>().is_err() {
+            return invalid(format!(
+                "server.bind must be an address like 0.0.0.0:3001, got {:?}",
+                self.server.bind
+            ));
+        }
+        let encoder = &self.encoder;
+        if encoder.width == 0 || encoder.height == 0 || encoder.width % 2 != 0
+            || encoder.height % 2 != 0
+        {
+            return invalid(format!(
+                "encoder.width and encoder.height must be positive even numbers, got {}x{}",
+                encoder.width, encoder.height
+            ));
+        }
+        if let Err(message) = EncoderConfig::validate_bitrate(encoder.bitrate) {
+            return invalid(format!("encoder.{}", message));
+        }
+        if encoder.frame_duration_ms == 0 {
+            return invalid("encoder.frame_duration_ms must be positive".to_owned());
+        }
+        if let Some(max_frame_rate) = encoder.max_frame_rate {
+            if let Err(message) = encoder.validate_max_frame_rate(max_frame_rate) {
+                return invalid(format!("encoder.{}", message));
+            }
+        }
+        if encoder.slice_count == 0 {
+            return invalid("encoder.slice_count must be positive".to_owned());
+        }
+        let segment_duration_ms = self.segmenter.segment_duration_ms;
+        if segment_duration_ms == 0 || segment_duration_ms % encoder.frame_duration_ms != 0 {
+            return invalid(format!(
+                "segmenter.segment_duration_ms must be a positive multiple of \
+                 encoder.frame_duration_ms ({}), got {}",
+                encoder.frame_duration_ms, segment_duration_ms
+            ));
+        }
+        if self.playlist.live_edge_segments >= self.playlist.window_size {
+            return invalid(format!(
+                "playlist.window_size ({}) must be greater than playlist.live_edge_segments ({})",
+                self.playlist.window_size, self.playlist.live_edge_segments
+            ));
+        }
+        let source = &self.source;
+        if source.kind != SourceKind::Clock && source.path.is_empty() {
+            return invalid("source.path must not be empty, use \"-\" for stdin".to_owned());
+        }
+        if source.kind == SourceKind::Raw {
+            match source.frame_rate {
+                Some(frame_rate) if frame_rate > 0.0 => {}
+                _ => return invalid("source.frame_rate must be positive for raw input".to_owned()),
+            }
+        }
+        if self.health.stale_after_target_durations == 0 {
+            return invalid("health.stale_after_target_durations must be positive".to_owned());
+        }
+        let supervisor = &self.supervisor;
+        if supervisor.stall_after_target_durations == 0 {
+            return invalid("supervisor.stall_after_target_durations must be positive".to_owned());
+        }
+        if supervisor.initial_backoff_ms == 0
+            || supervisor.max_backoff_ms < supervisor.initial_backoff_ms
+        {
+            return invalid(format!(
+                "supervisor.initial_backoff_ms must be positive and at most \
+                 supervisor.max_backoff_ms ({}), got {}",
+                supervisor.max_backoff_ms, supervisor.initial_backoff_ms
+            ));
+        }
+        if self.cors.allowed_origins.is_empty() {
+            return invalid(
+                "cors.allowed_origins must not be empty, use [\"*\"] to allow any origin"
+                    .to_owned(),
+            );
+        }
+        Ok(())
+    }
+
+    // Serializes the configuration for logging with the admin token masked
+    pub fn to_redacted_toml(&self) -> String {
+        let mut config = self.clone();
+        if !config.admin.token.is_empty() {
+            config.admin.token = "<redacted>".to_owned();
+        }
+        toml::to_string(&config).expect("Failed to serialize configuration")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_admin_token() {
+        let mut config = Config::default();
+        config.admin.token = "s3cret-bearer-token".to_owned();
+        let logged = format!("Effective configuration:\n{}", config.to_redacted_toml());
+        assert!(!logged.contains("s3cret-bearer-token"));
+        assert!(logged.contains("token = \"<redacted>\""));
+        assert_eq!(config.admin.token, "s3cret-bearer-token");
+    }
+
+    #[test]
+    fn keeps_empty_admin_token() {
+        let config = Config::default();
+        assert!(config.to_redacted_toml().contains("token = \"\""));
+    }
+}
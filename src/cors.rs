@@ -0,0 +1,112 @@
+use hyper::{Method, StatusCode};
+use hyper::header::{AccessControlAllowMethods, AccessControlAllowOrigin, AccessControlMaxAge};
+use hyper::server::{Request, Response};
+
+pub enum AllowedOrigins {
+    Any,
+    List(Vec<String>),
+}
+
+pub struct CorsPolicy {
+    pub allowed_origins: AllowedOrigins,
+    pub exposed_headers: Vec<String>,
+    pub max_age_seconds: u32,
+}
+
+impl Default for CorsPolicy {
+    fn default() -> CorsPolicy {
+        CorsPolicy {
+            allowed_origins: AllowedOrigins::Any,
+            exposed_headers: vec![
+                "Content-Length".to_owned(),
+                "Content-Range".to_owned(),
+                "Accept-Ranges".to_owned(),
+                "ETag".to_owned(),
+                "Age".to_owned(),
+            ],
+            max_age_seconds: 600,
+        }
+    }
+}
+
+fn request_origin(req: &Request) -> Option<&str> {
+    req.headers()
+        .get_raw("Origin")
+        .and_then(|origin| origin.one())
+        .and_then(|origin| ::std::str::from_utf8(origin).ok())
+}
+
+impl CorsPolicy {
+    pub fn is_preflight(req: &Request) -> bool {
+        req.method() == &Method::Options
+            && req.headers()
+                .get_raw("Access-Control-Request-Method")
+                .is_some()
+    }
+
+    // Returns the Access-Control-Allow-Origin value for the request origin, if allowed.
+    fn allowed_origin(&self, req: &Request) -> Option<AccessControlAllowOrigin> {
+        let origin = match request_origin(req) {
+            Some(origin) => origin,
+            None => return None,
+        };
+        match self.allowed_origins {
+            AllowedOrigins::Any => Some(AccessControlAllowOrigin::Any),
+            AllowedOrigins::List(ref origins) => origins
+                .iter()
+                .find(|allowed| *allowed == origin)
+                .map(|allowed| AccessControlAllowOrigin::Value(allowed.clone())),
+        }
+    }
+
+    fn with_vary<B>(&self, mut response: Response<B>) -> Response<B> {
+        if let AllowedOrigins::List(_) = self.allowed_origins {
+            // The headers depend on the request origin, so caches must key on it
+            response.headers_mut().append_raw("Vary", "Origin");
+        }
+        response
+    }
+
+    // methods are the ones the requested resource takes
+    pub fn preflight_response<B>(&self, req: &Request, methods: Vec<Method>) -> Response<B> {
+        let allowed_origin = match self.allowed_origin(req) {
+            Some(allowed_origin) => allowed_origin,
+            None => return Response::new().with_status(StatusCode::Forbidden),
+        };
+        let mut response = self.with_vary(
+            Response::new()
+                .with_status(StatusCode::NoContent)
+                .with_header(allowed_origin),
+        );
+        response
+            .headers_mut()
+            .set(AccessControlAllowMethods(methods));
+        if let Some(headers) = req.headers()
+            .get_raw("Access-Control-Request-Headers")
+            .and_then(|headers| headers.one())
+        {
+            response
+                .headers_mut()
+                .set_raw("Access-Control-Allow-Headers", headers.to_vec());
+        }
+        response.with_header(AccessControlMaxAge(self.max_age_seconds))
+    }
+
+    // Responses to origins that are not allowed are left untouched so that the
+    // browser refuses to expose them.
+    pub fn apply<B>(&self, req: &Request, response: Response<B>) -> Response<B> {
+        let response = self.with_vary(response);
+        match self.allowed_origin(req) {
+            Some(allowed_origin) => {
+                let mut response = response.with_header(allowed_origin);
+                if !self.exposed_headers.is_empty() {
+                    response
+                        .headers_mut()
+                        .set_raw("Access-Control-Expose-Headers", self.exposed_headers.join(", "));
+                }
+                response
+            }
+            None => response,
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use config::SourceConfig;
+use error::{check_av, MediaError};
+use ffmpeg_sys::*;
+use h264::{self, AvcConfig, ProfileLevel};
+use std::cmp::max;
+use std::ffi::CString;
+use std::mem::zeroed;
+use std::os::raw::c_int;
+use std::ptr::null_mut;
+use std::slice::from_raw_parts;
+
+const MILLISECONDS: AVRational = AVRational { num: 1, den: 1000 };
+// Frame interval of streams that state no frame rate
+const DEFAULT_FRAME_INTERVAL_MS: u64 = 40;
+
+fn invalid(message: String) -> MediaError {
+    MediaError::InvalidInput(message)
+}
+
+// An encoded H.264 picture as an Annex B byte stream. Timestamps are on the
+// stream timeline, which starts at 0 and continues across loops.
+pub struct AccessUnit {
+    pub pts_ms: u64,
+    pub dts_ms: u64,
+    pub duration_ms: u64,
+    pub data: Vec<u8>,
+}
+
+// Reads the H.264 video stream of anything libavformat can open without
+// decoding it: files such as MP4, MKV and MPEG-TS, stdin, or an ingest URL
+// such as rtmp:// or srt:// when libavformat is built with the protocol. A
+// looping file is read again from the start with timestamps continuing from
+// its end.
+pub struct Demuxer {
+    looping: bool,
+    input: *mut AVFormatContext,
+    stream_index: c_int,
+    time_base: AVRational,
+    start_dts: i64,
+    avc_config: Option<AvcConfig>,
+    profile_level: ProfileLevel,
+    pass_start_ms: u64,
+    first_dts: Option<i64>,
+    packets_in_pass: u64,
+    last_dts_ms: Option<u64>,
+    frame_interval_ms: u64,
+}
+
+impl Drop for Demuxer {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.input.is_null() {
+                avformat_close_input(&mut self.input);
+            }
+        }
+    }
+}
+
+impl Demuxer {
+    // The stream must be H.264 of the given size, which is what the playlists
+    // announce.
+    pub fn open(
+        config: &SourceConfig,
+        width: usize,
+        height: usize,
+    ) -> Result<Demuxer, MediaError> {
+        let stdin = config.path == "-";
+        let url = CString::new(if stdin { "pipe:0" } else { config.path.as_str() })
+            .map_err(|_| invalid("source.path contains a NUL byte".to_owned()))?;
+        let mut demuxer = Demuxer {
+            // stdin can't be rewound
+            looping: config.looping && !stdin,
+            input: null_mut(),
+            stream_index: -1,
+            time_base: MILLISECONDS,
+            start_dts: 0,
+            avc_config: None,
+            profile_level: ProfileLevel {
+                profile_idc: 0,
+                constraint_flags: 0,
+                level_idc: 0,
+            },
+            pass_start_ms: 0,
+            first_dts: None,
+            packets_in_pass: 0,
+            last_dts_ms: None,
+            frame_interval_ms: DEFAULT_FRAME_INTERVAL_MS,
+        };
+        unsafe {
+            check_av(
+                "avformat_open_input",
+                avformat_open_input(&mut demuxer.input, url.as_ptr(), null_mut(), null_mut()),
+            )?;
+            check_av(
+                "avformat_find_stream_info",
+                avformat_find_stream_info(demuxer.input, null_mut()),
+            )?;
+            let index = av_find_best_stream(
+                demuxer.input,
+                AVMediaType::AVMEDIA_TYPE_VIDEO,
+                -1,
+                -1,
+                null_mut(),
+                0,
+            );
+            if index == AVERROR_STREAM_NOT_FOUND {
+                return Err(invalid("input has no video stream".to_owned()));
+            }
+            demuxer.stream_index = check_av("av_find_best_stream", index)?;
+
+            let stream = *(*demuxer.input).streams.offset(index as isize);
+            let codecpar = &*(*stream).codecpar;
+            if codecpar.codec_id != AVCodecID::AV_CODEC_ID_H264 {
+                return Err(MediaError::Unsupported(format!(
+                    "passthrough needs H.264 video, got {:?}",
+                    codecpar.codec_id
+                )));
+            }
+            if (codecpar.width as usize, codecpar.height as usize) != (width, height) {
+                return Err(MediaError::Unsupported(format!(
+                    "stream is {}x{}, but encoder.width x encoder.height is {}x{}",
+                    codecpar.width, codecpar.height, width, height
+                )));
+            }
+            let extradata: &[u8] = if codecpar.extradata.is_null() || codecpar.extradata_size <= 0 {
+                &[]
+            } else {
+                from_raw_parts(codecpar.extradata, codecpar.extradata_size as usize)
+            };
+            demuxer.avc_config = AvcConfig::parse(extradata)?;
+            // Annex B extradata holds the parameter sets with start codes
+            let sps = h264::nal_units(extradata).filter_map(ProfileLevel::from_sps).next();
+            demuxer.profile_level = match (&demuxer.avc_config, sps) {
+                (&Some(ref avc_config), _) => avc_config.profile_level,
+                (&None, Some(sps)) => sps,
+                (&None, None) => ProfileLevel {
+                    profile_idc: codecpar.profile as u8,
+                    constraint_flags: 0,
+                    level_idc: codecpar.level as u8,
+                },
+            };
+            demuxer.time_base = (*stream).time_base;
+            if (*stream).start_time != AV_NOPTS_VALUE {
+                demuxer.start_dts = (*stream).start_time;
+            }
+            let frame_rate = (*stream).avg_frame_rate;
+            if frame_rate.num > 0 && frame_rate.den > 0 {
+                demuxer.frame_interval_ms =
+                    max(1000 * frame_rate.den as u64 / frame_rate.num as u64, 1);
+            }
+        }
+        Ok(demuxer)
+    }
+
+    pub fn profile_level(&self) -> ProfileLevel {
+        self.profile_level
+    }
+
+    // Returns the next access unit, or None at the end of input.
+    pub fn access_unit(&mut self) -> Result<Option<AccessUnit>, MediaError> {
+        loop {
+            let mut packet: AVPacket = unsafe { zeroed() };
+            unsafe { av_init_packet(&mut packet) };
+            let r = unsafe { av_read_frame(self.input, &mut packet) };
+            if r == AVERROR_EOF {
+                if !self.rewind()? {
+                    return Ok(None);
+                }
+                continue;
+            }
+            check_av("av_read_frame", r)?;
+            let access_unit = if packet.stream_index == self.stream_index && packet.size > 0 {
+                Some(self.convert(&packet))
+            } else {
+                None
+            };
+            unsafe { av_packet_unref(&mut packet) };
+            if let Some(access_unit) = access_unit {
+                return access_unit.map(Some);
+            }
+        }
+    }
+
+    fn convert(&mut self, packet: &AVPacket) -> Result<AccessUnit, MediaError> {
+        let payload = unsafe { from_raw_parts(packet.data, packet.size as usize) };
+        let data = match self.avc_config {
+            Some(ref avc_config) => avc_config.to_annex_b(payload)?,
+            None => payload.to_vec(),
+        };
+
+        let to_ms = |ts: i64, first_dts: i64, time_base: AVRational| unsafe {
+            av_rescale_q(max(ts - first_dts, 0), time_base, MILLISECONDS) as u64
+        };
+        // Packets without a timestamp follow the previous one. The timeline
+        // never goes back and the muxer needs increasing DTS.
+        let next_ms = self.last_dts_ms.map_or(0, |last| last + 1);
+        let dts_ms = if packet.dts == AV_NOPTS_VALUE {
+            self.last_dts_ms.map_or(0, |last| last + self.frame_interval_ms)
+        } else {
+            let first_dts = *self.first_dts.get_or_insert(packet.dts);
+            self.pass_start_ms + to_ms(packet.dts, first_dts, self.time_base)
+        };
+        let dts_ms = max(dts_ms, next_ms);
+        let pts_ms = match (packet.pts, self.first_dts) {
+            (pts, Some(first_dts)) if pts != AV_NOPTS_VALUE => {
+                self.pass_start_ms + to_ms(pts, first_dts, self.time_base)
+            }
+            _ => dts_ms,
+        };
+        let duration_ms = if packet.duration > 0 {
+            to_ms(packet.duration, 0, self.time_base)
+        } else {
+            self.frame_interval_ms
+        };
+        if self.packets_in_pass > 0 {
+            if let Some(last_dts_ms) = self.last_dts_ms {
+                if dts_ms > last_dts_ms {
+                    self.frame_interval_ms = dts_ms - last_dts_ms;
+                }
+            }
+        }
+        self.last_dts_ms = Some(dts_ms);
+        self.packets_in_pass += 1;
+        Ok(AccessUnit {
+            pts_ms: max(pts_ms, dts_ms),
+            dts_ms,
+            duration_ms: max(duration_ms, 1),
+            data,
+        })
+    }
+
+    // Seeks back to the start to loop the input.
+    fn rewind(&mut self) -> Result<bool, MediaError> {
+        if !self.looping {
+            return Ok(false);
+        }
+        if self.packets_in_pass == 0 {
+            return Err(invalid("input has no frames to loop".to_owned()));
+        }
+        check_av("av_seek_frame", unsafe {
+            av_seek_frame(
+                self.input,
+                self.stream_index,
+                self.start_dts,
+                AVSEEK_FLAG_BACKWARD as c_int,
+            )
+        })?;
+        self.pass_start_ms = self.last_dts_ms.unwrap_or(0) + self.frame_interval_ms;
+        self.first_dts = None;
+        self.packets_in_pass = 0;
+        Ok(true)
+    }
+}
@@ -0,0 +1,289 @@
+use error::{check_openh264, MediaError};
+use openh264_sys::*;
+use std::os::raw::{c_int, c_void};
+use std::ptr::null_mut;
+use std::slice::from_raw_parts;
+
+pub struct EncoderParams {
+    pub width: usize,
+    pub height: usize,
+    pub bitrate: u64,
+    pub max_frame_rate: f32,
+    pub profile: EProfileIdc,
+    pub slice_count: u32,
+    pub thread_count: u16,
+}
+
+// An I420 image with rows padded to 16 pixels.
+pub struct Picture {
+    pub width: usize,
+    pub height: usize,
+    pub timestamp_ms: u64,
+    // Y, U and V
+    pub strides: [usize; 3],
+    pub planes: [Vec<u8>; 3],
+}
+
+impl Picture {
+    pub fn new(width: usize, height: usize) -> Picture {
+        let y_stride = (width + 15) / 16 * 16;
+        let uv_stride = y_stride / 2;
+        let uv_height = (height + 1) / 2;
+        Picture {
+            width,
+            height,
+            timestamp_ms: 0,
+            strides: [y_stride, uv_stride, uv_stride],
+            planes: [
+                vec![0; y_stride * height],
+                vec![0; uv_stride * uv_height],
+                vec![0; uv_stride * uv_height],
+            ],
+        }
+    }
+
+    // Whether every plane holds the rows its stride and the size claim, so
+    // that the encoder and the scaler stay within the buffers.
+    pub fn is_consistent(&self) -> bool {
+        let uv_height = (self.height + 1) / 2;
+        let rows = [self.height, uv_height, uv_height];
+        let widths = [self.width, (self.width + 1) / 2, (self.width + 1) / 2];
+        (0..3).all(|plane| {
+            self.strides[plane] >= widths[plane]
+                && self.planes[plane].len() >= self.strides[plane] * rows[plane]
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameType {
+    Idr,
+    I,
+    P,
+    IpMixed,
+    // Dropped by rate control, there is no data
+    Skip,
+    Invalid,
+    Unknown(EVideoFrameType),
+}
+
+impl FrameType {
+    fn from_raw(frame_type: EVideoFrameType) -> FrameType {
+        if frame_type == videoFrameTypeIDR {
+            FrameType::Idr
+        } else if frame_type == videoFrameTypeI {
+            FrameType::I
+        } else if frame_type == videoFrameTypeP {
+            FrameType::P
+        } else if frame_type == videoFrameTypeIPMixed {
+            FrameType::IpMixed
+        } else if frame_type == videoFrameTypeSkip {
+            FrameType::Skip
+        } else if frame_type == videoFrameTypeInvalid {
+            FrameType::Invalid
+        } else {
+            FrameType::Unknown(frame_type)
+        }
+    }
+
+    // Label in metrics::FRAME_TYPES, for frames that carry data
+    pub fn label(&self) -> Option<&'static str> {
+        match *self {
+            FrameType::Idr => Some("idr"),
+            FrameType::I => Some("i"),
+            FrameType::P => Some("p"),
+            FrameType::IpMixed => Some("ipmixed"),
+            FrameType::Skip | FrameType::Invalid | FrameType::Unknown(_) => None,
+        }
+    }
+}
+
+pub struct EncodedFrame {
+    pub frame_type: FrameType,
+    // Annex B byte stream of the base spatial layer
+    pub data: Vec<u8>,
+}
+
+// An openh264 encoder, uninitialized and destroyed on drop.
+pub struct Encoder {
+    raw: *mut *const ISVCEncoderVtbl,
+    width: usize,
+    height: usize,
+}
+
+impl Encoder {
+    pub fn new(params: &EncoderParams) -> Result<Encoder, MediaError> {
+        let mut raw = null_mut();
+        check_openh264("WelsCreateSVCEncoder", unsafe {
+            WelsCreateSVCEncoder(&mut raw)
+        })?;
+        if raw.is_null() {
+            return Err(MediaError::Null("WelsCreateSVCEncoder"));
+        }
+        // Dropping an encoder that failed to initialize is fine, openh264
+        // ignores Uninitialize then.
+        let mut encoder = Encoder {
+            raw,
+            width: params.width,
+            height: params.height,
+        };
+        encoder.initialize(params)?;
+        Ok(encoder)
+    }
+
+    fn vtable(&self) -> &ISVCEncoderVtbl {
+        unsafe { &**self.raw }
+    }
+
+    fn initialize(&mut self, params: &EncoderParams) -> Result<(), MediaError> {
+        let get_default_params = self.vtable()
+            .GetDefaultParams
+            .ok_or(MediaError::Null("GetDefaultParams"))?;
+        let initialize_ext = self.vtable()
+            .InitializeExt
+            .ok_or(MediaError::Null("InitializeExt"))?;
+
+        let mut param = SEncParamExt::default();
+        check_openh264("GetDefaultParams", unsafe {
+            get_default_params(self.raw, &mut param)
+        })?;
+
+        let bitrate = params.bitrate as i32;
+        param.iUsageType = CAMERA_VIDEO_REAL_TIME;
+        param.fMaxFrameRate = params.max_frame_rate;
+        param.iMaxBitrate = UNSPECIFIED_BIT_RATE as i32;
+        param.iSpatialLayerNum = 1; // layer number at spatial level
+        param.bSimulcastAVC = true;
+        param.iMultipleThreadIdc = params.thread_count;
+        param.sSpatialLayers[0].uiProfileIdc = params.profile;
+        param.sSpatialLayers[0].iVideoWidth = params.width as i32;
+        param.sSpatialLayers[0].iVideoHeight = params.height as i32;
+        param.sSpatialLayers[0].fFrameRate = params.max_frame_rate;
+        param.sSpatialLayers[0].iSpatialBitrate = bitrate;
+        param.sSpatialLayers[0].iMaxSpatialBitrate = UNSPECIFIED_BIT_RATE as i32;
+        param.sSpatialLayers[0].sSliceArgument.uiSliceMode = SM_FIXEDSLCNUM_SLICE;
+        param.sSpatialLayers[0].sSliceArgument.uiSliceNum = params.slice_count;
+        param.iPicWidth = params.width as i32;
+        param.iPicHeight = params.height as i32;
+        param.iTargetBitrate = bitrate;
+
+        check_openh264("InitializeExt", unsafe {
+            initialize_ext(self.raw, &mut param)
+        })?;
+
+        let mut video_format = videoFormatI420 as c_int;
+        self.set_option(
+            "SetOption(ENCODER_OPTION_DATAFORMAT)",
+            ENCODER_OPTION_DATAFORMAT,
+            &mut video_format,
+        )?;
+        let mut rc_frame_skip = 0 as c_int;
+        self.set_option(
+            "SetOption(ENCODER_OPTION_RC_FRAME_SKIP)",
+            ENCODER_OPTION_RC_FRAME_SKIP,
+            &mut rc_frame_skip,
+        )
+    }
+
+    fn set_option<T>(
+        &mut self,
+        call: &'static str,
+        option: ENCODER_OPTION,
+        value: &mut T,
+    ) -> Result<(), MediaError> {
+        let set_option = self.vtable()
+            .SetOption
+            .ok_or(MediaError::Null("SetOption"))?;
+        check_openh264(call, unsafe {
+            set_option(self.raw, option, value as *mut T as *mut c_void)
+        })
+    }
+
+    pub fn set_bitrate(&mut self, bitrate: u64) -> Result<(), MediaError> {
+        let mut bitrate_info = SBitrateInfo {
+            iLayer: SPATIAL_LAYER_ALL,
+            iBitrate: bitrate as c_int,
+        };
+        self.set_option(
+            "SetOption(ENCODER_OPTION_BITRATE)",
+            ENCODER_OPTION_BITRATE,
+            &mut bitrate_info,
+        )
+    }
+
+    pub fn set_max_frame_rate(&mut self, max_frame_rate: f32) -> Result<(), MediaError> {
+        let mut frame_rate = max_frame_rate;
+        self.set_option(
+            "SetOption(ENCODER_OPTION_FRAME_RATE)",
+            ENCODER_OPTION_FRAME_RATE,
+            &mut frame_rate,
+        )
+    }
+
+    // Makes the next encoded frame an IDR frame
+    pub fn force_intra_frame(&mut self) -> Result<(), MediaError> {
+        let force_intra_frame = self.vtable()
+            .ForceIntraFrame
+            .ok_or(MediaError::Null("ForceIntraFrame"))?;
+        check_openh264("ForceIntraFrame", unsafe {
+            force_intra_frame(self.raw, true)
+        })
+    }
+
+    pub fn encode(&mut self, picture: &Picture) -> Result<EncodedFrame, MediaError> {
+        if picture.width != self.width || picture.height != self.height
+            || !picture.is_consistent()
+        {
+            return Err(MediaError::InvalidInput(format!(
+                "picture of {}x{} for an encoder of {}x{}",
+                picture.width, picture.height, self.width, self.height
+            )));
+        }
+        let encode_frame = self.vtable()
+            .EncodeFrame
+            .ok_or(MediaError::Null("EncodeFrame"))?;
+
+        let mut info = SFrameBSInfo::default();
+        let mut pic = SSourcePicture::default();
+        pic.uiTimeStamp = picture.timestamp_ms as i64;
+        pic.iPicWidth = picture.width as i32;
+        pic.iPicHeight = picture.height as i32;
+        pic.iColorFormat = videoFormatI420 as i32;
+        for plane in 0..3 {
+            pic.iStride[plane] = picture.strides[plane] as i32;
+            // openh264 only reads the source picture
+            pic.pData[plane] = picture.planes[plane].as_ptr() as *mut u8;
+        }
+        check_openh264("EncodeFrame", unsafe {
+            encode_frame(self.raw, &mut pic, &mut info)
+        })?;
+
+        let mut data = Vec::new();
+        for layer in &info.sLayerInfo[..info.iLayerNum as usize] {
+            if layer.uiSpatialId != 0 {
+                continue;
+            }
+            let nal_lengths =
+                unsafe { from_raw_parts(layer.pNalLengthInByte, layer.iNalCount as usize) };
+            let size: c_int = nal_lengths.iter().sum();
+            if size > 0 {
+                data.extend_from_slice(unsafe { from_raw_parts(layer.pBsBuf, size as usize) });
+            }
+        }
+        Ok(EncodedFrame {
+            frame_type: FrameType::from_raw(info.eFrameType),
+            data,
+        })
+    }
+}
+
+impl Drop for Encoder {
+    fn drop(&mut self) {
+        unsafe {
+            if let Some(uninitialize) = self.vtable().Uninitialize {
+                uninitialize(self.raw);
+            }
+            WelsDestroySVCEncoder(self.raw);
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use ffmpeg_sys::av_strerror;
+use std::error::Error;
+use std::ffi::CStr;
+use std::fmt;
+use std::io;
+use std::os::raw::{c_char, c_int};
+
+// Failure of a libav*, openh264 or ImageMagick call in the media pipeline.
+// Each variant names the failing call.
+#[derive(Debug)]
+pub enum MediaError {
+    // A libav* function returned a negative error code
+    Av(&'static str, c_int),
+    // An openh264 function returned a non-zero CM_RETURN code
+    Openh264(&'static str, c_int),
+    Magick(&'static str, &'static str),
+    // A call without an error code reported failure
+    Failed(&'static str),
+    // An allocation or lookup returned null
+    Null(&'static str),
+    Io(&'static str, io::Error),
+    // Input that a frame source can't read, or a frame or picture of another
+    // size or format than the scaler or encoder was created for
+    InvalidInput(String),
+    // Input the configuration doesn't fit, which a recreated pipeline would
+    // fail on the same way
+    Unsupported(String),
+}
+
+fn av_error_string(code: c_int) -> String {
+    let mut buf = [0 as c_char; 128];
+    if unsafe { av_strerror(code, buf.as_mut_ptr(), buf.len()) } < 0 {
+        return "unknown error".to_owned();
+    }
+    unsafe { CStr::from_ptr(buf.as_ptr()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+impl fmt::Display for MediaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MediaError::Av(call, code) => {
+                write!(f, "{} failed: {} ({})", call, code, av_error_string(code))
+            }
+            MediaError::Openh264(call, code) => write!(f, "{} failed: {}", call, code),
+            MediaError::Magick(call, message) => write!(f, "{} failed: {}", call, message),
+            MediaError::Failed(call) => write!(f, "{} failed", call),
+            MediaError::Null(call) => write!(f, "{} returned null", call),
+            MediaError::Io(call, ref err) => write!(f, "{} failed: {}", call, err),
+            MediaError::InvalidInput(ref message) => write!(f, "Invalid input: {}", message),
+            MediaError::Unsupported(ref message) => write!(f, "Unsupported input: {}", message),
+        }
+    }
+}
+
+impl Error for MediaError {
+    fn description(&self) -> &str {
+        "media pipeline error"
+    }
+}
+
+pub fn check_av(call: &'static str, code: c_int) -> Result<c_int, MediaError> {
+    if code < 0 {
+        Err(MediaError::Av(call, code))
+    } else {
+        Ok(code)
+    }
+}
+
+pub fn check_openh264(call: &'static str, code: c_int) -> Result<(), MediaError> {
+    if code != 0 {
+        Err(MediaError::Openh264(call, code))
+    } else {
+        Ok(())
+    }
+}
+
+pub fn check_null<T>(call: &'static str, ptr: *mut T) -> Result<*mut T, MediaError> {
+    if ptr.is_null() {
+        Err(MediaError::Null(call))
+    } else {
+        Ok(ptr)
+    }
+}
@@ -0,0 +1,400 @@
+use error::MediaError;
+
+pub const NAL_SLICE: u8 = 1;
+pub const NAL_IDR_SLICE: u8 = 5;
+pub const NAL_SPS: u8 = 7;
+
+const START_CODE: &[u8] = &[0, 0, 0, 1];
+
+// NAL units of an Annex B byte stream, without their start codes.
+pub struct NalUnits<'a> {
+    data: &'a [u8],
+}
+
+pub fn nal_units(data: &[u8]) -> NalUnits {
+    NalUnits { data }
+}
+
+// Returns the offset of the next 00 00 01 start code and its length, which
+// includes a leading zero byte of a 4 byte start code.
+fn find_start_code(data: &[u8]) -> Option<(usize, usize)> {
+    let position = data.windows(3).position(|window| window == [0, 0, 1])?;
+    if position > 0 && data[position - 1] == 0 {
+        Some((position - 1, 4))
+    } else {
+        Some((position, 3))
+    }
+}
+
+impl<'a> Iterator for NalUnits<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let (start, length) = find_start_code(self.data)?;
+        let rest = &self.data[start + length..];
+        let end = find_start_code(rest).map_or(rest.len(), |(end, _)| end);
+        self.data = &rest[end..];
+        Some(&rest[..end])
+    }
+}
+
+pub fn nal_type(nal: &[u8]) -> u8 {
+    nal.first().map_or(0, |header| header & 0x1f)
+}
+
+// Whether an access unit holds an IDR picture, which a segment can start with
+pub fn is_idr(access_unit: &[u8]) -> bool {
+    nal_units(access_unit).any(|nal| nal_type(nal) == NAL_IDR_SLICE)
+}
+
+// Reads the unsigned Exp-Golomb codes at the start of a slice header. Bytes
+// are read as they are, so emulation prevention bytes must not occur there,
+// which holds for the first two codes of any slice.
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn bit(&mut self) -> Option<u32> {
+        let byte = self.data.get(self.position / 8)?;
+        let bit = (byte >> (7 - self.position % 8)) & 1;
+        self.position += 1;
+        Some(bit as u32)
+    }
+
+    fn unsigned_exp_golomb(&mut self) -> Option<u32> {
+        let mut leading_zeros = 0;
+        while self.bit()? == 0 {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                return None;
+            }
+        }
+        let mut value = 0;
+        for _ in 0..leading_zeros {
+            value = value << 1 | self.bit()?;
+        }
+        Some((1 << leading_zeros) - 1 + value)
+    }
+}
+
+// Label in metrics::FRAME_TYPES of the first slice of an access unit
+pub fn frame_label(access_unit: &[u8]) -> Option<&'static str> {
+    let slice = nal_units(access_unit)
+        .find(|nal| nal_type(nal) == NAL_SLICE || nal_type(nal) == NAL_IDR_SLICE)?;
+    if nal_type(slice) == NAL_IDR_SLICE {
+        return Some("idr");
+    }
+    let mut reader = BitReader {
+        data: &slice[1..],
+        position: 0,
+    };
+    let _first_mb_in_slice = reader.unsigned_exp_golomb()?;
+    match reader.unsigned_exp_golomb()? % 5 {
+        0 | 3 => Some("p"),
+        1 => Some("b"),
+        _ => Some("i"),
+    }
+}
+
+// profile_idc, the constraint_set flags byte and level_idc of an SPS
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProfileLevel {
+    pub profile_idc: u8,
+    pub constraint_flags: u8,
+    pub level_idc: u8,
+}
+
+impl ProfileLevel {
+    // Reads the bytes following the NAL unit header of an SPS
+    pub fn from_sps(sps: &[u8]) -> Option<ProfileLevel> {
+        if nal_type(sps) != NAL_SPS || sps.len() < 4 {
+            return None;
+        }
+        Some(ProfileLevel {
+            profile_idc: sps[1],
+            constraint_flags: sps[2],
+            level_idc: sps[3],
+        })
+    }
+
+    // RFC 6381 codecs parameter
+    pub fn codec(&self) -> String {
+        format!(
+            "avc1.{:02x}{:02x}{:02x}",
+            self.profile_idc, self.constraint_flags, self.level_idc
+        )
+    }
+
+    // AVCodecParameters profile, which marks constrained baseline and the
+    // intra profiles the way libavcodec does
+    pub fn av_profile(&self) -> i32 {
+        const CONSTRAINED: i32 = 1 << 9;
+        const INTRA: i32 = 1 << 11;
+        let profile = self.profile_idc as i32;
+        match self.profile_idc {
+            66 if self.constraint_flags & 0x40 != 0 => profile | CONSTRAINED,
+            110 | 122 | 244 if self.constraint_flags & 0x10 != 0 => profile | INTRA,
+            _ => profile,
+        }
+    }
+}
+
+fn invalid(message: &str) -> MediaError {
+    MediaError::InvalidInput(message.to_owned())
+}
+
+// AVCDecoderConfigurationRecord of H.264 in MP4 and Matroska, whose packets
+// hold length prefixed NAL units instead of an Annex B byte stream.
+pub struct AvcConfig {
+    pub profile_level: ProfileLevel,
+    length_size: usize,
+    parameter_sets: Vec<u8>,
+}
+
+impl AvcConfig {
+    // Returns None for extradata that is not an avcC record, in which case
+    // packets are Annex B already.
+    pub fn parse(extradata: &[u8]) -> Result<Option<AvcConfig>, MediaError> {
+        if extradata.first() != Some(&1) {
+            return Ok(None);
+        }
+        if extradata.len() < 6 {
+            return Err(invalid("avcC record is cut short"));
+        }
+        let profile_level = ProfileLevel {
+            profile_idc: extradata[1],
+            constraint_flags: extradata[2],
+            level_idc: extradata[3],
+        };
+        let length_size = (extradata[4] & 0x03) as usize + 1;
+        let mut parameter_sets = Vec::new();
+        let mut rest = &extradata[5..];
+        // SPS count in the low 5 bits, then the PPS count in a whole byte
+        for &mask in &[0x1f, 0xff] {
+            let (&count, tail) = rest.split_first()
+                .ok_or_else(|| invalid("avcC record is cut short"))?;
+            rest = tail;
+            for _ in 0..(count & mask) {
+                if rest.len() < 2 {
+                    return Err(invalid("avcC record is cut short"));
+                }
+                let size = (rest[0] as usize) << 8 | rest[1] as usize;
+                if rest.len() < 2 + size {
+                    return Err(invalid("avcC record is cut short"));
+                }
+                parameter_sets.extend_from_slice(START_CODE);
+                parameter_sets.extend_from_slice(&rest[2..2 + size]);
+                rest = &rest[2 + size..];
+            }
+        }
+        Ok(Some(AvcConfig {
+            profile_level,
+            length_size,
+            parameter_sets,
+        }))
+    }
+
+    // Converts a packet to Annex B. IDR access units get the parameter sets
+    // in front unless they carry their own, so that every segment decodes on
+    // its own.
+    pub fn to_annex_b(&self, packet: &[u8]) -> Result<Vec<u8>, MediaError> {
+        let mut nals = Vec::new();
+        let mut rest = packet;
+        while !rest.is_empty() {
+            if rest.len() < self.length_size {
+                return Err(invalid("NAL unit length is cut short"));
+            }
+            let size = rest[..self.length_size]
+                .iter()
+                .fold(0, |size, &byte| size << 8 | byte as usize);
+            rest = &rest[self.length_size..];
+            if rest.len() < size {
+                return Err(invalid("NAL unit is cut short"));
+            }
+            nals.push(&rest[..size]);
+            rest = &rest[size..];
+        }
+
+        let mut data = Vec::with_capacity(packet.len() + self.parameter_sets.len());
+        let idr = nals.iter().any(|nal| nal_type(nal) == NAL_IDR_SLICE);
+        if idr && !nals.iter().any(|nal| nal_type(nal) == NAL_SPS) {
+            data.extend_from_slice(&self.parameter_sets);
+        }
+        for nal in nals {
+            data.extend_from_slice(START_CODE);
+            data.extend_from_slice(nal);
+        }
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPS: &[u8] = &[0x67, 0x64, 0x00, 0x28];
+    const PPS: &[u8] = &[0x68, 0xee];
+    // High profile 4.0 with 4 byte NAL unit lengths, one SPS and one PPS
+    const AVCC: &[u8] = &[
+        0x01, 0x64, 0x00, 0x28, 0xff, 0xe1, 0x00, 0x04, 0x67, 0x64, 0x00, 0x28, 0x01, 0x00, 0x02,
+        0x68, 0xee,
+    ];
+
+    fn message<T>(result: Result<T, MediaError>) -> String {
+        match result {
+            Err(MediaError::InvalidInput(message)) => message,
+            Err(err) => panic!("expected invalid input, got {}", err),
+            Ok(_) => panic!("expected invalid input"),
+        }
+    }
+
+    fn length_prefixed(nals: &[&[u8]]) -> Vec<u8> {
+        let mut packet = Vec::new();
+        for nal in nals {
+            let size = nal.len() as u32;
+            packet.extend_from_slice(&[(size >> 24) as u8, (size >> 16) as u8, (size >> 8) as u8]);
+            packet.push(size as u8);
+            packet.extend_from_slice(nal);
+        }
+        packet
+    }
+
+    fn annex_b(nals: &[&[u8]]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for nal in nals {
+            data.extend_from_slice(START_CODE);
+            data.extend_from_slice(nal);
+        }
+        data
+    }
+
+    #[test]
+    fn finds_3_and_4_byte_start_codes() {
+        assert_eq!(find_start_code(&[0, 0, 1, 0x65]), Some((0, 3)));
+        assert_eq!(find_start_code(&[0, 0, 0, 1, 0x65]), Some((0, 4)));
+        assert_eq!(find_start_code(&[0x65, 0, 0, 1, 0x41]), Some((1, 3)));
+        assert_eq!(find_start_code(&[0x65, 0, 0, 0, 1, 0x41]), Some((1, 4)));
+        assert_eq!(find_start_code(&[0, 0, 2, 0, 0]), None);
+    }
+
+    #[test]
+    fn splits_nal_units_at_mixed_start_codes() {
+        let data = [
+            0, 0, 0, 1, 0x67, 1, 2, 0, 0, 1, 0x68, 3, 0, 0, 0, 1, 0x65, 4, 5,
+        ];
+        let nals: Vec<&[u8]> = nal_units(&data).collect();
+        assert_eq!(nals, vec![&[0x67, 1, 2][..], &[0x68, 3][..], &[0x65, 4, 5][..]]);
+        assert!(is_idr(&data));
+        assert!(!is_idr(&data[..12]));
+        assert_eq!(nal_units(&[1, 2, 3]).count(), 0);
+    }
+
+    #[test]
+    fn maps_slice_types_to_frame_labels() {
+        // first_mb_in_slice 0, then slice_type, both unsigned Exp-Golomb
+        let slices: &[(&[u8], &str)] = &[
+            (&[0x41, 0xc0], "p"),       // 0
+            (&[0x01, 0xa0], "b"),       // 1
+            (&[0x41, 0xb0], "i"),       // 2
+            (&[0x41, 0x98], "p"),       // 5
+            (&[0x01, 0x9c], "b"),       // 6
+            (&[0x41, 0x88, 0x00], "i"), // 7
+            (&[0x65, 0x88, 0x00], "idr"),
+        ];
+        for &(slice, label) in slices {
+            assert_eq!(frame_label(&annex_b(&[SPS, slice])), Some(label), "{:?}", slice);
+        }
+    }
+
+    #[test]
+    fn has_no_frame_label_without_a_complete_slice_header() {
+        assert_eq!(frame_label(&annex_b(&[SPS, PPS])), None);
+        // slice_type is cut short
+        assert_eq!(frame_label(&annex_b(&[&[0x41, 0x80]])), None);
+        assert_eq!(frame_label(&annex_b(&[&[0x41]])), None);
+    }
+
+    #[test]
+    fn reads_profile_and_level() {
+        let profile_level = ProfileLevel::from_sps(SPS).unwrap();
+        assert_eq!(profile_level.codec(), "avc1.640028");
+        assert_eq!(profile_level.av_profile(), 100);
+        assert_eq!(ProfileLevel::from_sps(&SPS[..3]), None);
+        assert_eq!(ProfileLevel::from_sps(PPS), None);
+
+        let baseline = ProfileLevel::from_sps(&[0x67, 66, 0xc0, 41]).unwrap();
+        assert_eq!(baseline.codec(), "avc1.42c029");
+        assert_eq!(baseline.av_profile(), 578);
+    }
+
+    #[test]
+    fn parses_avcc() {
+        let avc_config = AvcConfig::parse(AVCC).unwrap().unwrap();
+        assert_eq!(avc_config.profile_level, ProfileLevel::from_sps(SPS).unwrap());
+        assert_eq!(avc_config.length_size, 4);
+        assert_eq!(avc_config.parameter_sets, annex_b(&[SPS, PPS]));
+    }
+
+    #[test]
+    fn takes_annex_b_extradata_for_no_avcc() {
+        assert!(AvcConfig::parse(&annex_b(&[SPS, PPS])).unwrap().is_none());
+        assert!(AvcConfig::parse(&[]).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_truncated_avcc() {
+        // Header, SPS count, SPS length, SPS and PPS count
+        for &length in &[5, 6, 7, 11, 12, 14, 16] {
+            let result = AvcConfig::parse(&AVCC[..length]);
+            assert!(message(result).contains("cut short"), "{} bytes", length);
+        }
+    }
+
+    #[test]
+    fn inserts_parameter_sets_in_front_of_idr_pictures() {
+        let avc_config = AvcConfig::parse(AVCC).unwrap().unwrap();
+        let idr: &[u8] = &[0x65, 0x88, 0x00];
+        let data = avc_config.to_annex_b(&length_prefixed(&[idr])).unwrap();
+        assert_eq!(data, annex_b(&[SPS, PPS, idr]));
+    }
+
+    #[test]
+    fn keeps_parameter_sets_of_the_access_unit() {
+        let avc_config = AvcConfig::parse(AVCC).unwrap().unwrap();
+        let sps: &[u8] = &[0x67, 0x64, 0x00, 0x29];
+        let idr: &[u8] = &[0x65, 0x88, 0x00];
+        let data = avc_config
+            .to_annex_b(&length_prefixed(&[sps, PPS, idr]))
+            .unwrap();
+        assert_eq!(data, annex_b(&[sps, PPS, idr]));
+    }
+
+    #[test]
+    fn leaves_other_pictures_alone() {
+        let avc_config = AvcConfig::parse(AVCC).unwrap().unwrap();
+        let p: &[u8] = &[0x41, 0xc0];
+        let data = avc_config.to_annex_b(&length_prefixed(&[p])).unwrap();
+        assert_eq!(data, annex_b(&[p]));
+    }
+
+    #[test]
+    fn reads_2_byte_nal_unit_lengths() {
+        let mut avcc = AVCC.to_vec();
+        avcc[4] = 0xfd;
+        let avc_config = AvcConfig::parse(&avcc).unwrap().unwrap();
+        let data = avc_config.to_annex_b(&[0, 2, 0x41, 0xc0, 0, 1, 0x0c]).unwrap();
+        assert_eq!(data, annex_b(&[&[0x41, 0xc0], &[0x0c]]));
+    }
+
+    #[test]
+    fn rejects_truncated_nal_units() {
+        let avc_config = AvcConfig::parse(AVCC).unwrap().unwrap();
+        let packet = length_prefixed(&[&[0x41, 0xc0]]);
+        let result = avc_config.to_annex_b(&packet[..3]);
+        assert!(message(result).contains("length is cut short"));
+        let result = avc_config.to_annex_b(&packet[..5]);
+        assert_eq!(message(result), "NAL unit is cut short");
+    }
+}
@@ -0,0 +1,67 @@
+use renditions::Renditions;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+// Liveness of the pipeline as seen by /readyz.
+pub struct Health {
+    renditions: Arc<Renditions>,
+    // Camcoder threads running. A stalled thread that the supervisor gave up
+    // on may still be counted.
+    camcoders_alive: AtomicUsize,
+    stale_after_target_durations: u32,
+}
+
+impl Health {
+    pub fn new(renditions: Arc<Renditions>, stale_after_target_durations: u32) -> Arc<Health> {
+        Arc::new(Health {
+            renditions,
+            camcoders_alive: AtomicUsize::new(0),
+            stale_after_target_durations,
+        })
+    }
+
+    // Marks a camcoder thread alive until the returned guard is dropped,
+    // which also happens when the thread panics.
+    pub fn camcoder_alive(health: &Arc<Health>) -> CamcoderAliveGuard {
+        health.camcoders_alive.fetch_add(1, Ordering::Relaxed);
+        CamcoderAliveGuard {
+            health: health.clone(),
+        }
+    }
+
+    // Returns the reason why viewers should not be routed here, if any.
+    pub fn readiness(&self) -> Result<(), String> {
+        if self.camcoders_alive.load(Ordering::Relaxed) == 0 {
+            return Err("camcoder is not running".to_owned());
+        }
+        for hls in self.renditions.all_hls() {
+            let snapshot = hls.snapshot();
+            let max_age = Duration::from_secs(
+                snapshot.target_duration() * self.stale_after_target_durations as u64,
+            );
+            match snapshot.newest_segment_age() {
+                Some(age) if age > max_age => {
+                    return Err(format!(
+                        "newest segment is {} ms old, limit is {} ms",
+                        age.as_secs() * 1000 + age.subsec_nanos() as u64 / 1_000_000,
+                        max_age.as_secs() * 1000
+                    ))
+                }
+                Some(_) => {}
+                None => return Err("no segment has been produced yet".to_owned()),
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct CamcoderAliveGuard {
+    health: Arc<Health>,
+}
+
+impl Drop for CamcoderAliveGuard {
+    fn drop(&mut self) {
+        self.health.camcoders_alive.fetch_sub(1, Ordering::Relaxed);
+    }
+}
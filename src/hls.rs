@@ -1,73 +1,377 @@
-use std::sync::{Arc, RwLock};
-use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::collections::{HashMap, VecDeque};
 use lazybytes::LazyBytes;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use bytes::Bytes;
+use compression::ContentCoding;
+use arc_swap::ArcSwap;
+use std::num::ParseIntError;
 
+pub const SEGMENT_PREFIX: &str = "segment";
+
+#[derive(Clone)]
 pub struct Segment {
     index: u64,
     duration_ms: u64,
-    lazy_bytes: Arc<RwLock<LazyBytes>>,
+    // Encoding parameters changed from the previous segment
+    discontinuity: bool,
+    added_at: Instant,
+    lazy_bytes: Arc<LazyBytes>,
 }
 
-pub struct Hls {
+// Playlist rendered once per segment update and shared by every viewer.
+pub struct RenderedPlaylist {
+    pub last_modified: SystemTime,
+    pub etag: String,
+    plain: Bytes,
+    encoded: Mutex<HashMap<ContentCoding, Bytes>>,
+}
+
+impl RenderedPlaylist {
+    pub fn new(playlist: String, etag: String) -> RenderedPlaylist {
+        RenderedPlaylist {
+            last_modified: SystemTime::now(),
+            etag,
+            plain: Bytes::from(playlist),
+            encoded: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Compression runs at most once per coding and update.
+    pub fn encoded(&self, content_coding: ContentCoding) -> Bytes {
+        if content_coding == ContentCoding::Identity {
+            return self.plain.clone();
+        }
+        let mut encoded = self.encoded
+            .lock()
+            .expect("Failed to lock encoded playlists");
+        encoded
+            .entry(content_coding)
+            .or_insert_with(|| content_coding.encode(&self.plain))
+            .clone()
+    }
+}
+
+// Immutable view of the segment ring. A new snapshot is published on every
+// segment update, so readers never wait for the producer and vice versa.
+pub struct HlsSnapshot {
+    epoch: String,
+    segments: Vec<Segment>,
+    media_sequence: u64,
+    target_duration: u64,
+    ended: bool,
+    playlist: RenderedPlaylist,
+}
+
+impl Segment {
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+
+    pub fn duration_ms(&self) -> u64 {
+        self.duration_ms
+    }
+
+    pub fn lazy_bytes(&self) -> &Arc<LazyBytes> {
+        &self.lazy_bytes
+    }
+}
+
+impl HlsSnapshot {
+    fn new(epoch: &str, ring: &Ring) -> HlsSnapshot {
+        let segments = ring.segments.iter().cloned().collect::<Vec<_>>();
+        let playlist = generate_playlist(epoch, &segments, ring);
+        // Also changes when only the playlist options change
+        let mut etag = format!(
+            "{}.{}.{}",
+            segment_etag(epoch, ring.last_index),
+            ring.window_size,
+            ring.live_edge_segments
+        );
+        if ring.ended {
+            etag.push_str("-end");
+        }
+        HlsSnapshot {
+            epoch: epoch.to_owned(),
+            media_sequence: media_sequence(&segments, ring.live_edge_segments),
+            target_duration: ring.target_duration,
+            ended: ring.ended,
+            segments,
+            playlist: RenderedPlaylist::new(playlist, etag),
+        }
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    pub fn media_sequence(&self) -> u64 {
+        self.media_sequence
+    }
+
+    pub fn is_ended(&self) -> bool {
+        self.ended
+    }
+
+    // EXT-X-TARGETDURATION in seconds, which never changes
+    pub fn target_duration(&self) -> u64 {
+        self.target_duration
+    }
+
+    pub fn newest_segment_age(&self) -> Option<Duration> {
+        self.segments
+            .last()
+            .map(|segment| Instant::now().duration_since(segment.added_at))
+    }
+
+    pub fn playlist(&self) -> &RenderedPlaylist {
+        &self.playlist
+    }
+
+    pub fn segment_etag(&self, index: u64) -> String {
+        segment_etag(&self.epoch, index)
+    }
+
+    // Index of the segment a file name refers to. Names of another process
+    // refer to no segment of this one.
+    pub fn segment_index(&self, file: &str) -> Result<Option<u64>, ParseIntError> {
+        let name = file.trim_left_matches(SEGMENT_PREFIX).trim_right_matches(".ts");
+        let (epoch, index) = match name.find('-') {
+            Some(dash) => (&name[..dash], &name[dash + 1..]),
+            None => ("", name),
+        };
+        let index = index.parse::<u64>()?;
+        Ok(if epoch == self.epoch { Some(index) } else { None })
+    }
+
+    pub fn read_segment(&self, index: u64) -> Option<Arc<LazyBytes>> {
+        self.segments
+            .iter()
+            .find(|segment| segment.index == index)
+            .map(|segment| segment.lazy_bytes.clone())
+    }
+}
+
+// Segment indices restart from 1 when the process restarts, so segment file
+// names and tags also include the creation time of the ring. Caches may keep
+// a completed segment forever under its name.
+fn epoch(created_at: SystemTime) -> String {
+    let created_at = created_at
+        .duration_since(UNIX_EPOCH)
+        .expect("Failed to get hls creation time");
+    format!("{:x}{:08x}", created_at.as_secs(), created_at.subsec_nanos())
+}
+
+fn segment_etag(epoch: &str, index: u64) -> String {
+    format!("{}-{}", epoch, index)
+}
+
+// Segments may not last longer than the target duration, which a playlist
+// may not change.
+fn target_duration(segment_duration_ms: u64) -> u64 {
+    ::std::cmp::max(1, (segment_duration_ms + 999) / 1000)
+}
+
+fn media_sequence(segments: &[Segment], live_edge_segments: usize) -> u64 {
+    segments
+        .iter()
+        .rev()
+        .skip(live_edge_segments)
+        .next()
+        .map(|segment| segment.index)
+        .unwrap_or(0)
+}
+
+fn generate_playlist(epoch: &str, segments: &[Segment], ring: &Ring) -> String {
+    let skip = ring.live_edge_segments;
+    let sequence = media_sequence(segments, skip);
+    let start_offset_ms = segments
+        .iter()
+        .rev()
+        .take(skip)
+        .map(|segment| segment.duration_ms)
+        .sum::<u64>();
+    let mut playlist = format!(
+        r"#EXTM3U
+#EXT-X-VERSION:6
+#EXT-X-TARGETDURATION:{}
+#EXT-X-START:TIME-OFFSET=-{},PRECISE=NO
+#EXT-X-MEDIA-SEQUENCE:{}
+#EXT-X-DISCONTINUITY-SEQUENCE:{}
+
+",
+        ring.target_duration,
+        start_offset_ms as f64 / 1000.0,
+        sequence,
+        ring.discontinuity_sequence
+    );
+    for segment in segments {
+        if segment.discontinuity {
+            playlist.push_str("#EXT-X-DISCONTINUITY\n");
+        }
+        playlist.push_str(&format!(
+            "#EXTINF:{},\n{}{}-{:09}.ts\n",
+            segment.duration_ms as f64 / 1000.0,
+            SEGMENT_PREFIX,
+            epoch,
+            segment.index
+        ));
+    }
+    if ring.ended {
+        playlist.push_str("#EXT-X-ENDLIST\n");
+    }
+    playlist
+}
+
+struct Ring {
+    // Of the producer allowed to write
+    generation: u64,
     last_index: u64,
     segments: VecDeque<Segment>,
+    // Discontinuities that have slid out of the window
+    discontinuity_sequence: u64,
+    // In seconds
+    target_duration: u64,
+    window_size: usize,
+    live_edge_segments: usize,
+    ended: bool,
+}
+
+impl Ring {
+    fn trim(&mut self) {
+        while self.segments.len() > self.window_size {
+            if let Some(segment) = self.segments.pop_front() {
+                if segment.discontinuity {
+                    self.discontinuity_sequence += 1;
+                }
+            }
+        }
+    }
+}
+
+pub struct Hls {
+    epoch: String,
+    // Only the producer locks this, so it is never contended by readers
+    ring: Mutex<Ring>,
+    snapshot: ArcSwap<HlsSnapshot>,
 }
 
 impl Hls {
-    pub fn new() -> Arc<RwLock<Hls>> {
-        let hls = Hls {
+    pub fn new(
+        window_size: usize,
+        live_edge_segments: usize,
+        segment_duration_ms: u64,
+    ) -> Arc<Hls> {
+        let epoch = epoch(SystemTime::now());
+        let ring = Ring {
+            generation: 0,
             last_index: 0,
             segments: VecDeque::new(),
+            discontinuity_sequence: 0,
+            target_duration: target_duration(segment_duration_ms),
+            window_size,
+            live_edge_segments,
+            ended: false,
         };
+        let hls = Hls {
+            snapshot: ArcSwap::from(Arc::new(HlsSnapshot::new(&epoch, &ring))),
+            epoch,
+            ring: Mutex::new(ring),
+        };
+
+        Arc::new(hls)
+    }
 
-        Arc::new(RwLock::new(hls))
+    fn lock(&self) -> MutexGuard<Ring> {
+        self.ring.lock().expect("Failed to lock hls segments")
+    }
+
+    // Returns the ring unless a later producer has started.
+    fn lock_for(&self, generation: u64) -> Option<MutexGuard<Ring>> {
+        let ring = self.lock();
+        if ring.generation == generation {
+            Some(ring)
+        } else {
+            None
+        }
     }
 
-    pub fn add_new_segment(&mut self, duration_ms: u64, lazy_bytes: Arc<RwLock<LazyBytes>>) {
-        self.last_index += 1;
-        self.segments.push_back(Segment {
-            index: self.last_index,
+    // Starts a producer and returns the generation it writes with. Writes of
+    // earlier producers, which may still be running if they were abandoned,
+    // are ignored from now on, and the segment they left in progress is
+    // completed so that its readers do not wait forever.
+    pub fn start_generation(&self) -> u64 {
+        let mut ring = self.lock();
+        ring.generation += 1;
+        if let Some(segment) = ring.segments.back() {
+            segment.lazy_bytes.complete();
+        }
+        ring.generation
+    }
+
+    pub fn add_new_segment(
+        &self,
+        generation: u64,
+        duration_ms: u64,
+        lazy_bytes: Arc<LazyBytes>,
+        discontinuity: bool,
+    ) {
+        let mut ring = match self.lock_for(generation) {
+            Some(ring) => ring,
+            None => return,
+        };
+        ring.last_index += 1;
+        let index = ring.last_index;
+        ring.segments.push_back(Segment {
+            index,
             lazy_bytes,
             duration_ms,
+            discontinuity,
+            added_at: Instant::now(),
         });
-        while self.segments.len() > 10 {
-            self.segments.pop_front();
-        }
+        ring.trim();
+        self.publish(&ring);
     }
 
-    pub fn generate_playlist(&self) -> String {
-        let skip = 1;
-        let sequence = self.segments
-            .iter()
-            .rev()
-            .skip(skip)
-            .next()
-            .map(|segment| segment.index)
-            .unwrap_or(0);
-        let mut playlist = format!(
-            r"#EXTM3U
-#EXT-X-VERSION:6
-#EXT-X-TARGETDURATION:1
-#EXT-X-START:TIME-OFFSET=-0.35,PRECISE=NO
-#EXT-X-MEDIA-SEQUENCE:{}
+    pub fn set_playlist_options(&self, window_size: usize, live_edge_segments: usize) {
+        let mut ring = self.lock();
+        ring.window_size = window_size;
+        ring.live_edge_segments = live_edge_segments;
+        ring.trim();
+        self.publish(&ring);
+    }
 
-",
-            sequence
-        );
-        for segment in &self.segments {
-            playlist.push_str(&format!(
-                "#EXTINF:{},\nsegment{:09}.ts\n",
-                segment.duration_ms as f64 / 1000.0,
-                segment.index
-            ));
+    // Marks the stream as finished. A newest segment that was announced with
+    // the full segment duration before it was written is shortened to
+    // last_duration_ms, or dropped if nothing was written. None leaves the
+    // segments of a producer that adds them once complete as they are.
+    pub fn end(&self, generation: u64, last_duration_ms: Option<u64>) {
+        let mut ring = match self.lock_for(generation) {
+            Some(ring) => ring,
+            None => return,
+        };
+        match last_duration_ms {
+            Some(0) => {
+                ring.segments.pop_back();
+            }
+            Some(last_duration_ms) => {
+                if let Some(segment) = ring.segments.back_mut() {
+                    segment.duration_ms = last_duration_ms;
+                }
+            }
+            None => {}
         }
-        playlist
+        ring.ended = true;
+        self.publish(&ring);
     }
 
-    pub fn read_segment(&self, index: u64) -> Option<Arc<RwLock<LazyBytes>>> {
-        self.segments
-            .iter()
-            .find(|segment| segment.index == index)
-            .map(|segment| segment.lazy_bytes.clone())
+    fn publish(&self, ring: &Ring) {
+        self.snapshot
+            .store(Arc::new(HlsSnapshot::new(&self.epoch, ring)));
+    }
+
+    // Lock-free and wait-free
+    pub fn snapshot(&self) -> Arc<HlsSnapshot> {
+        self.snapshot.load()
     }
 }
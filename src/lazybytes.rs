@@ -1,27 +1,215 @@
+use arc_swap::{ArcSwap, ArcSwapOption, Lease};
 use futures::{Async, Poll};
 use futures::stream::Stream;
+use futures::task::{self, Task};
 use hyper;
-use std::sync::{Arc, RwLock};
+use std::mem;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
 use bytes::Bytes;
-use std::sync::TryLockError;
 
+// Append only linked list of immutable chunks. Appending links one node after
+// the last one, so it never copies already written chunks, and every reader
+// shares the same chunks without copying.
+//
+// Readers follow the links without locking. The mutex only guards the tasks
+// of readers waiting for more.
 pub struct LazyBytes {
-    pub bytes: Bytes,
-    pub completion: bool,
+    // Empty node the chunks are linked after
+    head: Arc<Node>,
+    // Lags behind the last node while an append is in progress, so the links
+    // are followed from it.
+    tail: ArcSwap<Node>,
+    readers: Mutex<Vec<Task>>,
+    // Live LazyBytesStreams, i.e. viewers reading while the segment is written
+    streams: AtomicUsize,
+}
+
+struct Node {
+    chunk: Bytes,
+    // Length of the chunks up to and including this one
+    end: usize,
+    // None until the next chunk is appended or the bytes are completed
+    next: ArcSwapOption<Link>,
+}
+
+enum Link {
+    Next(Arc<Node>),
+    Completed(SystemTime),
+}
+
+impl Node {
+    fn new(chunk: Bytes, end: usize) -> Arc<Node> {
+        Arc::new(Node {
+            chunk,
+            end,
+            next: ArcSwapOption::from(None),
+        })
+    }
+}
+
+impl Drop for Node {
+    // Unlinks the following nodes one at a time, as dropping them recursively
+    // could overflow the stack on a long segment.
+    fn drop(&mut self) {
+        let mut next = self.next.swap(None);
+        while let Some(link) = next.take() {
+            if let Ok(Link::Next(node)) = Arc::try_unwrap(link) {
+                if let Ok(node) = Arc::try_unwrap(node) {
+                    next = node.next.swap(None);
+                }
+            }
+        }
+    }
+}
+
+impl LazyBytes {
+    pub fn new() -> Arc<LazyBytes> {
+        let head = Node::new(Bytes::new(), 0);
+        let lazy_bytes = LazyBytes {
+            tail: ArcSwap::from(head.clone()),
+            head,
+            readers: Mutex::new(Vec::new()),
+            streams: AtomicUsize::new(0),
+        };
+
+        Arc::new(lazy_bytes)
+    }
+
+    fn lock_readers(&self) -> MutexGuard<Vec<Task>> {
+        self.readers.lock().expect("Failed to lock lazy bytes readers")
+    }
+
+    // Follows the links from the tail to the last node and returns it with the
+    // completion time if completed.
+    fn last(&self) -> (Arc<Node>, Option<SystemTime>) {
+        let mut node = self.tail.load();
+        loop {
+            let next = match node.next.load() {
+                None => return (node, None),
+                Some(link) => match *link {
+                    Link::Next(ref next) => next.clone(),
+                    Link::Completed(completed_at) => return (node, Some(completed_at)),
+                },
+            };
+            node = next;
+        }
+    }
+
+    // Links after the last node unless another link won the race, as when
+    // completing an abandoned segment races with its writer.
+    fn link(&self, last: &Node, link: Link) -> bool {
+        let open: Option<Arc<Link>> = None;
+        let previous = last.next.compare_and_swap(&open, Some(Arc::new(link)));
+        if !Lease::is_null(&previous) {
+            return false;
+        }
+        let readers = mem::replace(&mut *self.lock_readers(), Vec::new());
+        for reader in readers {
+            reader.notify();
+        }
+        true
+    }
+
+    // Chunks appended after completion are dropped, as when the writer was
+    // abandoned and its segment completed by its successor.
+    pub fn append(&self, chunk: Bytes) {
+        if chunk.is_empty() {
+            return;
+        }
+        loop {
+            let (last, completed_at) = self.last();
+            if completed_at.is_some() {
+                return;
+            }
+            let node = Node::new(chunk.clone(), last.end + chunk.len());
+            if self.link(&last, Link::Next(node.clone())) {
+                self.tail.store(node);
+                return;
+            }
+        }
+    }
+
+    pub fn complete(&self) {
+        loop {
+            let (last, completed_at) = self.last();
+            if completed_at.is_some()
+                || self.link(&last, Link::Completed(SystemTime::now()))
+            {
+                return;
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.last().0.end
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.last().1.is_some()
+    }
+
+    pub fn stream_count(&self) -> usize {
+        self.streams.load(Ordering::SeqCst)
+    }
+
+    // Returns all chunks and the completion time once the writer has completed,
+    // otherwise None.
+    pub fn completed_chunks(&self) -> Option<(Vec<Bytes>, SystemTime)> {
+        if !self.is_complete() {
+            return None;
+        }
+        let mut chunks = Vec::new();
+        let mut node = self.head.clone();
+        loop {
+            let next = match node.next.load() {
+                None => return None,
+                Some(link) => match *link {
+                    Link::Next(ref next) => next.clone(),
+                    Link::Completed(completed_at) => return Some((chunks, completed_at)),
+                },
+            };
+            chunks.push(next.chunk.clone());
+            node = next;
+        }
+    }
+
+    fn register_reader(&self) {
+        let mut readers = self.lock_readers();
+        if !readers.iter().any(|reader| reader.will_notify_current()) {
+            readers.push(task::current());
+        }
+    }
 }
 
 pub struct LazyBytesStream {
-    processed_bytes: usize,
-    segment: Arc<RwLock<LazyBytes>>,
+    // Last node read, the head before the first chunk
+    node: Arc<Node>,
+    segment: Arc<LazyBytes>,
 }
 
 impl LazyBytesStream {
-    pub fn new(segment: Arc<RwLock<LazyBytes>>) -> LazyBytesStream {
+    pub fn new(segment: Arc<LazyBytes>) -> LazyBytesStream {
+        segment.streams.fetch_add(1, Ordering::SeqCst);
         LazyBytesStream {
-            processed_bytes: 0,
+            node: segment.head.clone(),
             segment,
         }
     }
+
+    fn next_chunk(&mut self) -> Option<Option<Bytes>> {
+        let next = match self.node.next.load() {
+            None => return None,
+            Some(link) => match *link {
+                Link::Next(ref next) => next.clone(),
+                Link::Completed(_) => return Some(None),
+            },
+        };
+        let chunk = next.chunk.clone();
+        self.node = next;
+        Some(Some(chunk))
+    }
 }
 
 impl Stream for LazyBytesStream {
@@ -29,33 +217,24 @@ impl Stream for LazyBytesStream {
     type Error = hyper::Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        let segment = match self.segment.try_read() {
-            Ok(segment) => segment,
-            Err(TryLockError::WouldBlock) => return Ok(Async::Ready(Some(hyper::Chunk::from("")))),
-            Err(TryLockError::Poisoned(err)) => {
-                panic!("Failed to try lock segment for read: {:?}", err)
+        let next = match self.next_chunk() {
+            Some(next) => next,
+            None => {
+                // The writer notifies after publishing, so looking again after
+                // registering catches a chunk published in between.
+                self.segment.register_reader();
+                match self.next_chunk() {
+                    Some(next) => next,
+                    None => return Ok(Async::NotReady),
+                }
             }
         };
-        let bytes = &segment.bytes;
-        if bytes.len() == self.processed_bytes {
-            if segment.completion {
-                Ok(Async::Ready(None))
-            } else {
-                // Ok(Async::NotReady)
-                Ok(Async::Ready(Some(hyper::Chunk::from(""))))
-            }
-        } else if bytes.len() > self.processed_bytes {
-            let ready = Async::Ready(Some(hyper::Chunk::from(
-                bytes.slice(self.processed_bytes, bytes.len()),
-            )));
-            self.processed_bytes = bytes.len();
-            Ok(ready)
-        } else {
-            panic!(
-                "Logic error: segment length is lessor than processed length: {} < {}",
-                bytes.len(),
-                self.processed_bytes
-            )
-        }
+        Ok(Async::Ready(next.map(hyper::Chunk::from)))
+    }
+}
+
+impl Drop for LazyBytesStream {
+    fn drop(&mut self) {
+        self.segment.streams.fetch_sub(1, Ordering::SeqCst);
     }
 }
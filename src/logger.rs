@@ -0,0 +1,67 @@
+use chrono::prelude::*;
+use config::{LogConfig, LogFormat};
+use log::{self, LevelFilter, Log, Metadata, Record};
+use serde_json;
+use std::io::{stderr, Write};
+
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    time: String,
+    level: &'a str,
+    target: &'a str,
+    message: String,
+}
+
+// Writes one line per record to stderr, as text or as a JSON object.
+struct Logger {
+    format: LogFormat,
+}
+
+impl Log for Logger {
+    // The level is kept in log::max_level() so that it can be reloaded
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let time = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        let line = match self.format {
+            LogFormat::Text => format!(
+                "{} {:<5} {}: {}",
+                time,
+                record.level(),
+                record.target(),
+                record.args()
+            ),
+            LogFormat::Json => serde_json::to_string(&JsonRecord {
+                time,
+                level: record.level().as_str(),
+                target: record.target(),
+                message: record.args().to_string(),
+            }).expect("Failed to serialize log record"),
+        };
+        let stderr = stderr();
+        let mut stderr = stderr.lock();
+        let _ = writeln!(stderr, "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = stderr().flush();
+    }
+}
+
+pub fn level_filter(level: &str) -> LevelFilter {
+    level
+        .parse()
+        .expect(&format!("Failed to parse log level {}", level))
+}
+
+pub fn init(config: &LogConfig) {
+    log::set_boxed_logger(Box::new(Logger {
+        format: config.format,
+    })).expect("Failed to set logger");
+    log::set_max_level(level_filter(&config.level));
+}
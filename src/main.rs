@@ -1,77 +1,194 @@
+extern crate arc_swap;
+extern crate brotli;
 extern crate bytes;
 extern crate chrono;
+extern crate clap;
 extern crate ffmpeg_sys;
+extern crate flate2;
 extern crate futures;
 extern crate hyper;
 extern crate libc;
+#[macro_use]
+extern crate log;
 extern crate magick_rust;
 extern crate openh264_sys;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 extern crate tokio_core;
+extern crate tokio_signal;
 extern crate tokio_timer;
+extern crate toml;
 
 mod service;
 mod hls;
 mod camcoder;
 mod mpegts;
 mod lazybytes;
+mod aac;
+mod tone;
+mod renditions;
+mod cors;
+mod compression;
+mod config;
+mod reload;
+mod metrics;
+mod logger;
+mod accesslog;
+mod health;
+mod status;
+mod error;
+mod supervisor;
+mod encoder;
+mod scaler;
+mod source;
+mod clock;
+mod y4m;
+mod playback;
+mod audio;
+mod h264;
+mod demux;
+mod remux;
+mod av;
 
 use hyper::server::Http;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
-use magick_rust::magick_wand_genesis;
-use tokio_core::reactor::{Core, Interval};
+use std::sync::mpsc::channel;
+use arc_swap::ArcSwap;
+use tokio_core::reactor::Handle;
 use std::time::Duration;
-use futures::Stream;
-use ffmpeg_sys::av_register_all;
+use futures::{Future, Stream};
+use tokio_signal::unix::{Signal, SIGHUP, SIGTERM};
+
+// Resolves on the first SIGINT or SIGTERM
+fn shutdown_signal(handle: &Handle) -> Box<Future<Item = (), Error = ()>> {
+    let sigint = tokio_signal::ctrl_c(handle).flatten_stream();
+    let sigterm = Signal::new(SIGTERM, handle)
+        .flatten_stream()
+        .map(|_| ());
+    Box::new(
+        sigint
+            .select(sigterm)
+            .into_future()
+            .map(|_| ())
+            .map_err(|(err, _)| error!("Failed to wait for signals: {}", err)),
+    )
+}
 
 fn main() {
     std::process::exit({
+        let config = match config::Config::from_args() {
+            Ok(config) => config,
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(2);
+            }
+        };
+        logger::init(&config.log);
+        info!("Effective configuration:\n{}", config.to_redacted_toml());
+
         let camcoder_thread_stop_writer = Arc::new(AtomicBool::new(false));
         let camcoder_thread_stop_reader = camcoder_thread_stop_writer.clone();
 
-        let camcoder_hls = hls::Hls::new();
-        let server_hls = camcoder_hls.clone();
+        let window_size = config.playlist.window_size;
+        let live_edge_segments = config.playlist.live_edge_segments;
+        let segment_duration_ms = config.segmenter.segment_duration_ms;
+        let camcoder_renditions = Arc::new(renditions::Renditions::new(
+            renditions::VideoRendition {
+                name: "video".to_owned(),
+                width: config.encoder.width,
+                height: config.encoder.height,
+                encoder: ArcSwap::from(Arc::new(config.encoder.clone())),
+                hls: hls::Hls::new(window_size, live_edge_segments, segment_duration_ms),
+            },
+            vec![
+                renditions::AudioRendition {
+                    name: "audio_en".to_owned(),
+                    display_name: "English".to_owned(),
+                    language: "en".to_owned(),
+                    tone_hz: 440.0,
+                    bandwidth: 128000,
+                    hls: hls::Hls::new(window_size, live_edge_segments, segment_duration_ms),
+                },
+                renditions::AudioRendition {
+                    name: "audio_ja".to_owned(),
+                    display_name: "Japanese".to_owned(),
+                    language: "ja".to_owned(),
+                    tone_hz: 660.0,
+                    bandwidth: 128000,
+                    hls: hls::Hls::new(window_size, live_edge_segments, segment_duration_ms),
+                },
+            ],
+        ));
+        let server_renditions = camcoder_renditions.clone();
+        let metrics = metrics::Metrics::new(camcoder_renditions.names());
+        let camcoder_metrics = metrics.clone();
+        let health = health::Health::new(
+            camcoder_renditions.clone(),
+            config.health.stale_after_target_durations,
+        );
+        let camcoder_health = health.clone();
+        let cors_policy = Arc::new(ArcSwap::from(Arc::new(config.cors.to_policy())));
+        let admin_config = Arc::new(ArcSwap::from(Arc::new(config.admin.clone())));
+        let (camcoder_command_sender, camcoder_command_receiver) = channel::<camcoder::Command>();
+        let mut reloader = reload::Reloader::new(
+            config.clone(),
+            camcoder_renditions.clone(),
+            cors_policy.clone(),
+            admin_config.clone(),
+            camcoder_command_sender.clone(),
+        );
+        let supervisor = supervisor::Supervisor::new(
+            camcoder_renditions.clone(),
+            camcoder_metrics,
+            camcoder_health,
+            camcoder_command_receiver,
+            &config,
+            camcoder_thread_stop_reader,
+        );
+        let camcoder_thread = std::thread::spawn(move || supervisor.run());
 
-        let camcoder_thread = std::thread::spawn(move || {
-            magick_wand_genesis();
-            unsafe { av_register_all() };
-            let ts_duration_ms = 350;
-            let tick_ms = 50; // 20fps
-            let mut camcoder =
-                camcoder::Camcorder::new(camcoder_hls.clone(), tick_ms, ts_duration_ms);
-            let mut core = Core::new().expect("Failed to allocate tokio_core::reactor::Core");
-            let handle = core.handle();
-            let interval_duration = Duration::from_millis(tick_ms);
-            let interval = Interval::new(interval_duration, &handle).expect(&format!(
-                "Failed to allocate interval: {:?}",
-                interval_duration
-            ));
-            core.run(interval.for_each(|_| {
-                if camcoder.run() && !camcoder_thread_stop_reader.as_ref().load(Ordering::Relaxed) {
-                    futures::future::ok(())
-                } else {
-                    futures::future::ok(())
-                    // futures::future::err(())
-                }
-            })).expect("Failed to run interval");
-        });
-
-        let addr_str = "0.0.0.0:3001";
-        let addr = addr_str
-            .parse()
-            .expect(&format!("Failed to parse address {}", addr_str));
-        let server = Http::new()
+        let addr = config.bind_addr();
+        let mut server = Http::new()
             .bind(&addr, move || {
-                Ok(service::HlsService::new(server_hls.clone()))
+                Ok(service::HlsService::new(
+                    server_renditions.clone(),
+                    cors_policy.clone(),
+                    metrics.clone(),
+                    health.clone(),
+                    admin_config.clone(),
+                    camcoder_command_sender.clone(),
+                ))
             })
             .expect(&format!("Failed to bind {:?}", addr));
+        server.shutdown_timeout(Duration::from_millis(config.server.shutdown_timeout_ms));
+        let handle = server.handle();
+        handle.spawn(
+            Signal::new(SIGHUP, &handle)
+                .flatten_stream()
+                .for_each(move |_| {
+                    info!("Reloading configuration");
+                    reloader.reload();
+                    Ok(())
+                })
+                .map_err(|err| error!("Failed to wait for SIGHUP: {}", err)),
+        );
+        // Stopping the camcoder completes the segments in progress, so that
+        // downloads streaming them can finish while the server drains.
+        let shutdown = shutdown_signal(&handle).then(move |_| {
+            info!("Shutting down");
+            camcoder_thread_stop_writer
+                .as_ref()
+                .store(true, Ordering::Relaxed);
+            Ok::<(), ()>(())
+        });
+        info!("Listening on {}", addr);
         server
-            .run()
+            .run_until(shutdown)
             .expect(&format!("Failed to run server {:?}", addr));
 
-        camcoder_thread_stop_writer
-            .as_ref()
-            .store(true, Ordering::Relaxed);
         camcoder_thread
             .join()
             .expect("Failed to join camcoder thread");
@@ -0,0 +1,407 @@
+use futures::{Async, Poll, Stream};
+use hyper;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+const SECONDS_BUCKETS: &[f64] = &[
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25
+];
+const FRAME_BYTES_BUCKETS: &[f64] = &[
+    1000.0, 2000.0, 5000.0, 10000.0, 20000.0, 50000.0, 100000.0, 200000.0, 500000.0
+];
+const SEGMENT_BYTES_BUCKETS: &[f64] = &[
+    16384.0, 32768.0, 65536.0, 131072.0, 262144.0, 524288.0, 1048576.0, 2097152.0, 4194304.0
+];
+const SEGMENT_SECONDS_BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.0, 4.0, 6.0, 10.0];
+
+pub const FRAME_TYPES: &[&str] = &["idr", "i", "p", "b", "ipmixed"];
+pub const ROUTES: &[&str] = &[
+    "segment", "playlist", "multivariant", "metrics", "api", "health", "static"
+];
+
+pub struct Counter {
+    value: AtomicUsize,
+}
+
+impl Counter {
+    fn new() -> Counter {
+        Counter {
+            value: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: usize) {
+        self.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> usize {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+pub struct Gauge {
+    value: AtomicIsize,
+}
+
+impl Gauge {
+    fn new() -> Gauge {
+        Gauge {
+            value: AtomicIsize::new(0),
+        }
+    }
+
+    pub fn inc(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.value.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> isize {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+// Observations are recorded as integers (nanoseconds, bytes, ...) and scaled
+// to the base unit when rendered, since there are no atomic floats.
+pub struct Histogram {
+    scale: f64,
+    bounds: &'static [f64],
+    buckets: Vec<AtomicUsize>,
+    count: AtomicUsize,
+    sum: AtomicUsize,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64], scale: f64) -> Histogram {
+        Histogram {
+            scale,
+            bounds,
+            buckets: bounds.iter().map(|_| AtomicUsize::new(0)).collect(),
+            count: AtomicUsize::new(0),
+            sum: AtomicUsize::new(0),
+        }
+    }
+
+    fn seconds() -> Histogram {
+        Histogram::new(SECONDS_BUCKETS, 1e-9)
+    }
+
+    pub fn observe(&self, value: usize) {
+        let scaled = value as f64 * self.scale;
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            if scaled <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn observe_duration(&self, duration: Duration) {
+        let nanos = duration.as_secs() * 1_000_000_000 + duration.subsec_nanos() as u64;
+        self.observe(nanos as usize);
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            out.push_str(&format!(
+                "{}_bucket{{{}{}le=\"{}\"}} {}\n",
+                name,
+                labels,
+                separator,
+                bound,
+                bucket.load(Ordering::Relaxed)
+            ));
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        out.push_str(&format!(
+            "{}_bucket{{{}{}le=\"+Inf\"}} {}\n",
+            name, labels, separator, count
+        ));
+        out.push_str(&format!(
+            "{}_sum{{{}}} {}\n",
+            name,
+            labels,
+            self.sum.load(Ordering::Relaxed) as f64 * self.scale
+        ));
+        out.push_str(&format!("{}_count{{{}}} {}\n", name, labels, count));
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+}
+
+fn labeled<'a, T>(series: &'a [(String, T)], label: &str) -> &'a T {
+    &series
+        .iter()
+        .find(|&&(ref name, _)| name == label)
+        .expect(&format!("Unknown metric label {}", label))
+        .1
+}
+
+// Pipeline and server metrics, rendered in the Prometheus text format.
+pub struct Metrics {
+    started_at: Instant,
+    pub frame_render_seconds: Histogram,
+    pub swscale_seconds: Histogram,
+    pub encode_seconds: Histogram,
+    frame_bytes: Vec<(String, Histogram)>,
+    pub encoded_frames: Counter,
+    pub skipped_frames: Counter,
+    pub invalid_frames: Counter,
+    pub camcoder_restarts: Counter,
+    segment_bytes: Vec<(String, Histogram)>,
+    segment_seconds: Vec<(String, Histogram)>,
+    pub active_segment_streams: Gauge,
+    served_bytes: Vec<(String, Counter)>,
+    responses: Mutex<BTreeMap<u16, usize>>,
+}
+
+impl Metrics {
+    pub fn new(rendition_names: Vec<String>) -> Arc<Metrics> {
+        let per_label = |labels: &[&str]| -> Vec<String> {
+            labels.iter().map(|label| label.to_string()).collect()
+        };
+        let frame_types = per_label(FRAME_TYPES);
+        let routes = per_label(ROUTES);
+        Arc::new(Metrics {
+            started_at: Instant::now(),
+            frame_render_seconds: Histogram::seconds(),
+            swscale_seconds: Histogram::seconds(),
+            encode_seconds: Histogram::seconds(),
+            frame_bytes: frame_types
+                .into_iter()
+                .map(|name| (name, Histogram::new(FRAME_BYTES_BUCKETS, 1.0)))
+                .collect(),
+            encoded_frames: Counter::new(),
+            skipped_frames: Counter::new(),
+            invalid_frames: Counter::new(),
+            camcoder_restarts: Counter::new(),
+            segment_bytes: rendition_names
+                .iter()
+                .map(|name| (name.clone(), Histogram::new(SEGMENT_BYTES_BUCKETS, 1.0)))
+                .collect(),
+            segment_seconds: rendition_names
+                .iter()
+                .map(|name| (name.clone(), Histogram::new(SEGMENT_SECONDS_BUCKETS, 1e-3)))
+                .collect(),
+            active_segment_streams: Gauge::new(),
+            served_bytes: routes
+                .into_iter()
+                .map(|name| (name, Counter::new()))
+                .collect(),
+            responses: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    pub fn frame_bytes(&self, frame_type: &str) -> &Histogram {
+        labeled(&self.frame_bytes, frame_type)
+    }
+
+    pub fn observe_segment(&self, rendition: &str, bytes: usize, duration_ms: u64) {
+        labeled(&self.segment_bytes, rendition).observe(bytes);
+        labeled(&self.segment_seconds, rendition).observe(duration_ms as usize);
+    }
+
+    pub fn served_bytes(&self, route: &str) -> &Counter {
+        labeled(&self.served_bytes, route)
+    }
+
+    pub fn count_response(&self, status: u16) {
+        let mut responses = self.responses.lock().expect("Failed to lock responses");
+        *responses.entry(status).or_insert(0) += 1;
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for &(name, help, histogram) in &[
+            (
+                "hls_frame_render_seconds",
+                "Time spent getting a frame from the frame source.",
+                &self.frame_render_seconds,
+            ),
+            (
+                "hls_swscale_seconds",
+                "Time spent converting RGB to I420.",
+                &self.swscale_seconds,
+            ),
+            (
+                "hls_encode_seconds",
+                "Time spent encoding a frame with openh264.",
+                &self.encode_seconds,
+            ),
+        ] {
+            header(&mut out, name, "histogram", help);
+            histogram.render(&mut out, name, "");
+        }
+
+        header(
+            &mut out,
+            "hls_encoded_frame_bytes",
+            "histogram",
+            "Size of encoded frames by frame type.",
+        );
+        for &(ref frame_type, ref histogram) in &self.frame_bytes {
+            histogram.render(
+                &mut out,
+                "hls_encoded_frame_bytes",
+                &format!("type=\"{}\"", frame_type),
+            );
+        }
+
+        header(
+            &mut out,
+            "hls_encoded_frames_total",
+            "counter",
+            "Frames the encoder produced.",
+        );
+        out.push_str(&format!(
+            "hls_encoded_frames_total {}\n",
+            self.encoded_frames.get()
+        ));
+        header(
+            &mut out,
+            "hls_skipped_frames_total",
+            "counter",
+            "Frames skipped by the encoder.",
+        );
+        out.push_str(&format!(
+            "hls_skipped_frames_total {}\n",
+            self.skipped_frames.get()
+        ));
+        header(
+            &mut out,
+            "hls_invalid_frames_total",
+            "counter",
+            "Frames the encoder reported as invalid.",
+        );
+        out.push_str(&format!(
+            "hls_invalid_frames_total {}\n",
+            self.invalid_frames.get()
+        ));
+        header(
+            &mut out,
+            "hls_camcoder_restarts_total",
+            "counter",
+            "Times the supervisor recreated a failed or stalled camcoder.",
+        );
+        out.push_str(&format!(
+            "hls_camcoder_restarts_total {}\n",
+            self.camcoder_restarts.get()
+        ));
+
+        header(
+            &mut out,
+            "hls_segment_bytes",
+            "histogram",
+            "Size of completed segments.",
+        );
+        for &(ref rendition, ref histogram) in &self.segment_bytes {
+            histogram.render(
+                &mut out,
+                "hls_segment_bytes",
+                &format!("rendition=\"{}\"", rendition),
+            );
+        }
+        header(
+            &mut out,
+            "hls_segment_duration_seconds",
+            "histogram",
+            "Duration of completed segments.",
+        );
+        for &(ref rendition, ref histogram) in &self.segment_seconds {
+            histogram.render(
+                &mut out,
+                "hls_segment_duration_seconds",
+                &format!("rendition=\"{}\"", rendition),
+            );
+        }
+
+        header(
+            &mut out,
+            "hls_active_segment_streams",
+            "gauge",
+            "Responses streaming a segment that is still being written.",
+        );
+        out.push_str(&format!(
+            "hls_active_segment_streams {}\n",
+            self.active_segment_streams.get()
+        ));
+
+        header(
+            &mut out,
+            "http_served_bytes_total",
+            "counter",
+            "Response body bytes by route.",
+        );
+        for &(ref route, ref counter) in &self.served_bytes {
+            out.push_str(&format!(
+                "http_served_bytes_total{{route=\"{}\"}} {}\n",
+                route,
+                counter.get()
+            ));
+        }
+
+        header(
+            &mut out,
+            "http_responses_total",
+            "counter",
+            "Responses by status code.",
+        );
+        let responses = self.responses.lock().expect("Failed to lock responses");
+        for (status, count) in responses.iter() {
+            out.push_str(&format!(
+                "http_responses_total{{code=\"{}\"}} {}\n",
+                status, count
+            ));
+        }
+        out
+    }
+}
+
+// Streams a segment that is still being written while counting the bytes
+// and the number of such streams.
+pub struct ActiveSegmentStream<S> {
+    stream: S,
+    metrics: Arc<Metrics>,
+}
+
+impl<S> ActiveSegmentStream<S> {
+    pub fn new(stream: S, metrics: Arc<Metrics>) -> ActiveSegmentStream<S> {
+        metrics.active_segment_streams.inc();
+        ActiveSegmentStream { stream, metrics }
+    }
+}
+
+impl<S: Stream<Item = hyper::Chunk>> Stream for ActiveSegmentStream<S> {
+    type Item = hyper::Chunk;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let poll = self.stream.poll()?;
+        if let Async::Ready(Some(ref chunk)) = poll {
+            self.metrics.served_bytes("segment").add(chunk.len());
+        }
+        Ok(poll)
+    }
+}
+
+impl<S> Drop for ActiveSegmentStream<S> {
+    fn drop(&mut self) {
+        self.metrics.active_segment_streams.dec();
+    }
+}
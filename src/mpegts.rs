@@ -5,17 +5,30 @@ use ffmpeg_sys::*;
 use libc;
 use std::ffi::CString;
 use lazybytes::LazyBytes;
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
+use bytes::Bytes;
+use aac::{AacEncoder, AacPacket};
+use error::{check_av, check_null, MediaError};
+use h264::ProfileLevel;
+use std::panic::{catch_unwind, AssertUnwindSafe};
 
 pub struct MpegTs {
     output_format: *mut AVFormatContext,
     output_io: *mut AVIOContext,
     output_video_stream: *mut AVStream,
+    output_audio_stream: *mut AVStream,
     output: Box<Output>,
+    // The trailer has been written
+    flushed: bool,
 }
 
 impl Drop for MpegTs {
     fn drop(&mut self) {
+        // A segment abandoned by a failed camcoder still has to end for the
+        // readers streaming it.
+        if !self.output.data.is_complete() {
+            self.output.data.complete();
+        }
         unsafe {
             if !self.output_format.is_null() {
                 avformat_free_context(self.output_format);
@@ -28,12 +41,15 @@ impl Drop for MpegTs {
             if !self.output_video_stream.is_null() {
                 self.output_video_stream = null_mut();
             }
+            if !self.output_audio_stream.is_null() {
+                self.output_audio_stream = null_mut();
+            }
         }
     }
 }
 
 struct Output {
-    data: Arc<RwLock<LazyBytes>>,
+    data: Arc<LazyBytes>,
 }
 
 unsafe extern "C" fn write_output(
@@ -41,13 +57,22 @@ unsafe extern "C" fn write_output(
     input_buf: *mut u8,
     input_buf_size: c_int,
 ) -> c_int {
+    if input_buf_size < 0 {
+        return AVERROR(libc::EINVAL);
+    }
     let output = &mut *(opaque as *mut Output);
-    {
-        let mut data = output.data.write().expect("Oops! w");
-        data.bytes
-            .extend_from_slice(from_raw_parts(input_buf, input_buf_size as usize));
+    // Unwinding into libavformat is undefined behavior, so report a panic as
+    // an I/O error of the write call instead.
+    let appended = catch_unwind(AssertUnwindSafe(|| {
+        output.data.append(Bytes::from(from_raw_parts(
+            input_buf,
+            input_buf_size as usize,
+        )))
+    }));
+    match appended {
+        Ok(()) => input_buf_size,
+        Err(_) => AVERROR(libc::EIO),
     }
-    input_buf_size
 }
 
 fn default_av_packet() -> AVPacket {
@@ -68,106 +93,170 @@ fn default_av_packet() -> AVPacket {
 }
 
 impl MpegTs {
-    pub unsafe fn new(width: usize, height: usize, lazy_bytes: Arc<RwLock<LazyBytes>>) -> MpegTs {
-        const AVIO_CTX_BUFFER_SIZE: usize = 8192;
-
-        let mut obj = MpegTs {
-            output_format: null_mut(),
-            output_io: null_mut(),
-            output: Box::new(Output { data: lazy_bytes }),
-            output_video_stream: null_mut(),
-        };
-        let output_file_name =
-            CString::new("output.ts").expect("Oops! can't parse output file name");
-        let mut r = avformat_alloc_output_context2(
-            &mut obj.output_format,
-            null_mut(),
-            null_mut(),
-            output_file_name.as_ptr(),
-        );
-        if r < 0 {
-            panic!("Failed to alloc output context: {}", r)
-        }
+    // The video stream is described by its size and the profile and level
+    // of its SPS.
+    pub fn new(
+        video: Option<(usize, usize, ProfileLevel)>,
+        aac_encoder: Option<&AacEncoder>,
+        lazy_bytes: Arc<LazyBytes>,
+    ) -> Result<MpegTs, MediaError> {
+        unsafe {
+            const AVIO_CTX_BUFFER_SIZE: usize = 8192;
 
-        let output_io_buf = av_mallocz(AVIO_CTX_BUFFER_SIZE) as *mut u8;
-        if output_io_buf.is_null() {
-            panic!("Failed to alloc output io buf");
-        }
+            let mut obj = MpegTs {
+                output_format: null_mut(),
+                output_io: null_mut(),
+                output: Box::new(Output { data: lazy_bytes }),
+                output_video_stream: null_mut(),
+                output_audio_stream: null_mut(),
+                flushed: false,
+            };
+            let output_file_name =
+                CString::new("output.ts").expect("Oops! can't parse output file name");
+            check_av(
+                "avformat_alloc_output_context2",
+                avformat_alloc_output_context2(
+                    &mut obj.output_format,
+                    null_mut(),
+                    null_mut(),
+                    output_file_name.as_ptr(),
+                ),
+            )?;
 
-        obj.output_io = avio_alloc_context(
-            output_io_buf,
-            AVIO_CTX_BUFFER_SIZE as i32,
-            1,
-            obj.output.as_mut() as *mut Output as *mut libc::c_void,
-            None,
-            Some(write_output),
-            None,
-        );
-        if obj.output_io.is_null() {
-            panic!("Failed to alloc output io");
-        }
+            let output_io_buf =
+                check_null("av_mallocz", av_mallocz(AVIO_CTX_BUFFER_SIZE) as *mut u8)?;
 
-        (*(obj.output_format)).pb = obj.output_io;
+            obj.output_io = avio_alloc_context(
+                output_io_buf,
+                AVIO_CTX_BUFFER_SIZE as i32,
+                1,
+                obj.output.as_mut() as *mut Output as *mut libc::c_void,
+                None,
+                Some(write_output),
+                None,
+            );
+            if obj.output_io.is_null() {
+                av_free(output_io_buf as *mut libc::c_void);
+                return Err(MediaError::Null("avio_alloc_context"));
+            }
 
-        obj.output_video_stream = avformat_new_stream(obj.output_format, null_mut());
-        if obj.output_video_stream.is_null() {
-            panic!("Failed to allocate new video stream")
-        }
-        // (*output_video_stream).time_base = (*input_stream).time_base;
-        let codecpar = &mut *(*obj.output_video_stream).codecpar;
-        codecpar.codec_type = AVMediaType::AVMEDIA_TYPE_VIDEO;
-        codecpar.codec_id = AVCodecID::AV_CODEC_ID_H264;
-        codecpar.bits_per_raw_sample = 8;
-        codecpar.profile = 578;
-        codecpar.level = 41;
-        codecpar.width = width as i32;
-        codecpar.height = height as i32;
-        codecpar.sample_aspect_ratio.den = 1;
-
-        r = avformat_write_header(obj.output_format, null_mut());
-        if r < 0 {
-            panic!("Failed to write ts header");
-        }
+            (*(obj.output_format)).pb = obj.output_io;
+
+            if let Some((width, height, profile_level)) = video {
+                obj.output_video_stream = check_null(
+                    "avformat_new_stream",
+                    avformat_new_stream(obj.output_format, null_mut()),
+                )?;
+                // (*output_video_stream).time_base = (*input_stream).time_base;
+                let codecpar = &mut *(*obj.output_video_stream).codecpar;
+                codecpar.codec_type = AVMediaType::AVMEDIA_TYPE_VIDEO;
+                codecpar.codec_id = AVCodecID::AV_CODEC_ID_H264;
+                codecpar.bits_per_raw_sample = 8;
+                codecpar.profile = profile_level.av_profile();
+                codecpar.level = profile_level.level_idc as i32;
+                codecpar.width = width as i32;
+                codecpar.height = height as i32;
+                codecpar.sample_aspect_ratio.den = 1;
+            }
+
+            if let Some(aac_encoder) = aac_encoder {
+                obj.output_audio_stream = check_null(
+                    "avformat_new_stream",
+                    avformat_new_stream(obj.output_format, null_mut()),
+                )?;
+                aac_encoder.fill_codec_parameters(&mut *(*obj.output_audio_stream).codecpar)?;
+                // The aac encoder delay makes the first audio timestamp negative. Keep it as is
+                // instead of shifting each segment by a different offset.
+                (*obj.output_format).avoid_negative_ts = 0;
+            }
+
+            check_av(
+                "avformat_write_header",
+                avformat_write_header(obj.output_format, null_mut()),
+            )?;
 
-        obj
+            Ok(obj)
+        }
     }
 
-    pub unsafe fn write(
+    // dts_ms differs from pts_ms for video with B-frames
+    pub fn write(
         &mut self,
         h264: &mut Vec<u8>,
-        start_ms: u64,
+        pts_ms: u64,
+        dts_ms: u64,
         frame_duration_ms: u64,
         key: bool,
-    ) {
+    ) -> Result<(), MediaError> {
+        if self.output_video_stream.is_null() {
+            panic!("Logic error: no video stream");
+        }
+        let stream = unsafe { &*self.output_video_stream };
         let mut packet = default_av_packet();
 
         if key {
             packet.flags |= AV_PKT_FLAG_KEY;
         }
-        let time_base = (*self.output_video_stream).time_base;
-        let den = time_base.den as i64;
-        let num = time_base.num as i64;
-        packet.pts = start_ms as i64 * den / (num * 1000);
-        packet.dts = packet.pts;
+        let den = stream.time_base.den as i64;
+        let num = stream.time_base.num as i64;
+        packet.pts = pts_ms as i64 * den / (num * 1000);
+        packet.dts = dts_ms as i64 * den / (num * 1000);
         packet.duration = frame_duration_ms as i64 * den / (num * 1000);
         packet.pos = -1;
-        packet.stream_index = 0;
+        packet.stream_index = stream.index;
         packet.data = h264.as_mut_ptr();
         packet.size = h264.len() as i32;
-        let r = av_interleaved_write_frame(self.output_format, &mut packet);
-        if r < 0 {
-            panic!("Failed to write video frame: {}", r)
+        self.write_packet(&mut packet)
+    }
+
+    pub fn write_audio(
+        &mut self,
+        aac_packet: &mut AacPacket,
+        sample_rate: i32,
+    ) -> Result<(), MediaError> {
+        if self.output_audio_stream.is_null() {
+            panic!("Logic error: no audio stream");
         }
+        let stream = unsafe { &*self.output_audio_stream };
+        let mut packet = default_av_packet();
+
+        packet.flags |= AV_PKT_FLAG_KEY;
+        let den = stream.time_base.den as i64;
+        let num = stream.time_base.num as i64;
+        packet.pts = aac_packet.pts * den / (num * sample_rate as i64);
+        packet.dts = packet.pts;
+        packet.duration = aac_packet.duration * den / (num * sample_rate as i64);
+        packet.pos = -1;
+        packet.stream_index = stream.index;
+        packet.data = aac_packet.data.as_mut_ptr();
+        packet.size = aac_packet.data.len() as i32;
+        self.write_packet(&mut packet)
     }
 
-    pub unsafe fn flush(&mut self) {
-        av_write_trailer(self.output_format);
-        {
-            let mut data = self.output
-                .data
-                .write()
-                .expect("Oops! write lock lazy bytes");
-            data.completion = true;
+    fn write_packet(&mut self, packet: &mut AVPacket) -> Result<(), MediaError> {
+        if self.flushed {
+            panic!("Logic error: write after flush");
         }
+        check_av("av_interleaved_write_frame", unsafe {
+            av_interleaved_write_frame(self.output_format, packet)
+        })?;
+        Ok(())
+    }
+
+    // Completes the segment even if the trailer can't be written, so that
+    // readers waiting for it are not left hanging. Flushing again does nothing.
+    pub fn flush(&mut self) -> Result<(), MediaError> {
+        if self.flushed {
+            return Ok(());
+        }
+        self.flushed = true;
+        let r = unsafe { av_write_trailer(self.output_format) };
+        self.output.data.complete();
+        check_av("av_write_trailer", r)?;
+        Ok(())
+    }
+
+    pub fn written_bytes(&self) -> usize {
+        self.output.data.len()
     }
 }
@@ -0,0 +1,291 @@
+use config::SourceConfig;
+use encoder::Picture;
+use error::{check_av, check_null, MediaError};
+use ffmpeg_sys::*;
+use libc;
+use scaler::Scaler;
+use source::{Frame, FrameSource};
+use std::cmp::max;
+use std::ffi::{CStr, CString};
+use std::mem::zeroed;
+use std::os::raw::c_int;
+use std::ptr::{null, null_mut};
+
+const MILLISECONDS: AVRational = AVRational { num: 1, den: 1000 };
+// Frame interval of streams that state no frame rate
+const DEFAULT_FRAME_INTERVAL_MS: u64 = 40;
+
+fn invalid(message: &str) -> MediaError {
+    MediaError::InvalidInput(message.to_owned())
+}
+
+// Decodes the best video stream of a media file that libavformat can demux,
+// such as MP4, MKV or MPEG-TS. Frames are scaled to the encoder size, and
+// dropped or repeated to follow the stream timeline. A looping file is
+// played again from the start with timestamps continuing from its end.
+pub struct PlaybackSource {
+    looping: bool,
+    input: *mut AVFormatContext,
+    decoder: *mut AVCodecContext,
+    // Decoded frame waiting until its time has come
+    frame: *mut AVFrame,
+    pending_ms: Option<u64>,
+    stream_index: c_int,
+    time_base: AVRational,
+    start_pts: i64,
+    scaler: Option<Scaler>,
+    picture: Picture,
+    // Whether the picture holds a frame yet
+    shown: bool,
+    // Source timeline in ms, which starts at 0 and continues across loops
+    pass_start_ms: u64,
+    first_pts: Option<i64>,
+    frames_in_pass: u64,
+    last_ms: u64,
+    frame_interval_ms: u64,
+}
+
+impl Drop for PlaybackSource {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.frame.is_null() {
+                av_frame_free(&mut self.frame);
+            }
+            if !self.decoder.is_null() {
+                avcodec_free_context(&mut self.decoder);
+            }
+            if !self.input.is_null() {
+                avformat_close_input(&mut self.input);
+            }
+        }
+    }
+}
+
+impl PlaybackSource {
+    pub fn new(
+        config: &SourceConfig,
+        width: usize,
+        height: usize,
+    ) -> Result<PlaybackSource, MediaError> {
+        let stdin = config.path == "-";
+        let url = CString::new(if stdin { "pipe:0" } else { config.path.as_str() })
+            .map_err(|_| invalid("source.path contains a NUL byte"))?;
+        let mut source = PlaybackSource {
+            // stdin can't be rewound
+            looping: config.looping && !stdin,
+            input: null_mut(),
+            decoder: null_mut(),
+            frame: null_mut(),
+            pending_ms: None,
+            stream_index: -1,
+            time_base: MILLISECONDS,
+            start_pts: 0,
+            scaler: None,
+            picture: Picture::new(width, height),
+            shown: false,
+            pass_start_ms: 0,
+            first_pts: None,
+            frames_in_pass: 0,
+            last_ms: 0,
+            frame_interval_ms: DEFAULT_FRAME_INTERVAL_MS,
+        };
+        unsafe { source.open(&url)? };
+        Ok(source)
+    }
+
+    unsafe fn open(&mut self, url: &CStr) -> Result<(), MediaError> {
+        check_av(
+            "avformat_open_input",
+            avformat_open_input(&mut self.input, url.as_ptr(), null_mut(), null_mut()),
+        )?;
+        check_av(
+            "avformat_find_stream_info",
+            avformat_find_stream_info(self.input, null_mut()),
+        )?;
+
+        let mut codec = null_mut();
+        let index = av_find_best_stream(
+            self.input,
+            AVMediaType::AVMEDIA_TYPE_VIDEO,
+            -1,
+            -1,
+            &mut codec,
+            0,
+        );
+        if index == AVERROR_STREAM_NOT_FOUND {
+            return Err(invalid("input has no video stream"));
+        }
+        if index == AVERROR_DECODER_NOT_FOUND {
+            return Err(invalid("no decoder for the video stream of the input"));
+        }
+        self.stream_index = check_av("av_find_best_stream", index)?;
+
+        let stream = *(*self.input).streams.offset(index as isize);
+        self.time_base = (*stream).time_base;
+        if (*stream).start_time != AV_NOPTS_VALUE {
+            self.start_pts = (*stream).start_time;
+        }
+        let frame_rate = (*stream).avg_frame_rate;
+        if frame_rate.num > 0 && frame_rate.den > 0 {
+            self.frame_interval_ms = max(1000 * frame_rate.den as u64 / frame_rate.num as u64, 1);
+        }
+
+        self.decoder = check_null("avcodec_alloc_context3", avcodec_alloc_context3(codec))?;
+        check_av(
+            "avcodec_parameters_to_context",
+            avcodec_parameters_to_context(self.decoder, (*stream).codecpar),
+        )?;
+        (*self.decoder).pkt_timebase = self.time_base;
+        check_av(
+            "avcodec_open2",
+            avcodec_open2(self.decoder, codec, null_mut()),
+        )?;
+        self.frame = check_null("av_frame_alloc", av_frame_alloc())?;
+        Ok(())
+    }
+
+    // Decodes the next frame and returns its time on the source timeline, or
+    // None once the decoder has returned every frame of the input.
+    unsafe fn decode(&mut self) -> Result<Option<u64>, MediaError> {
+        loop {
+            let r = avcodec_receive_frame(self.decoder, self.frame);
+            if r == 0 {
+                return Ok(Some(self.frame_ms()));
+            }
+            if r == AVERROR_EOF {
+                return Ok(None);
+            }
+            if r != AVERROR(libc::EAGAIN) {
+                check_av("avcodec_receive_frame", r)?;
+            }
+
+            let mut packet: AVPacket = zeroed();
+            av_init_packet(&mut packet);
+            let r = av_read_frame(self.input, &mut packet);
+            if r == AVERROR_EOF {
+                // Drains the frames the decoder holds back
+                check_av("avcodec_send_packet", avcodec_send_packet(self.decoder, null()))?;
+                continue;
+            }
+            check_av("av_read_frame", r)?;
+            let r = if packet.stream_index == self.stream_index {
+                avcodec_send_packet(self.decoder, &packet)
+            } else {
+                0
+            };
+            av_packet_unref(&mut packet);
+            // A corrupt packet costs a few frames, not the stream
+            if r == AVERROR_INVALIDDATA {
+                warn!("Skipped a packet the decoder could not read");
+                continue;
+            }
+            check_av("avcodec_send_packet", r)?;
+        }
+    }
+
+    // Frames without a timestamp follow the previous one
+    unsafe fn frame_ms(&mut self) -> u64 {
+        let pts = av_frame_get_best_effort_timestamp(self.frame);
+        let ms = if pts == AV_NOPTS_VALUE {
+            self.last_ms + self.frame_interval_ms
+        } else {
+            let first_pts = *self.first_pts.get_or_insert(pts);
+            let offset = av_rescale_q(max(pts - first_pts, 0), self.time_base, MILLISECONDS);
+            self.pass_start_ms + offset as u64
+        };
+        // The timeline never goes back, even if the file does
+        let ms = max(ms, self.last_ms);
+        if self.frames_in_pass > 0 && ms > self.last_ms {
+            self.frame_interval_ms = ms - self.last_ms;
+        }
+        self.last_ms = ms;
+        self.frames_in_pass += 1;
+        ms
+    }
+
+    // Seeks back to the start to loop the input.
+    unsafe fn rewind(&mut self) -> Result<bool, MediaError> {
+        if !self.looping {
+            return Ok(false);
+        }
+        if self.frames_in_pass == 0 {
+            return Err(invalid("input has no frames to loop"));
+        }
+        check_av(
+            "av_seek_frame",
+            av_seek_frame(
+                self.input,
+                self.stream_index,
+                self.start_pts,
+                AVSEEK_FLAG_BACKWARD as c_int,
+            ),
+        )?;
+        avcodec_flush_buffers(self.decoder);
+        self.pass_start_ms = self.last_ms + self.frame_interval_ms;
+        self.first_pts = None;
+        self.frames_in_pass = 0;
+        Ok(true)
+    }
+
+    // Scales the pending frame into the picture. The scaler follows changes
+    // of the decoded size or pixel format.
+    unsafe fn show_pending(&mut self) -> Result<(), MediaError> {
+        let frame = &*self.frame;
+        let format = (*self.decoder).pix_fmt;
+        if format == AVPixelFormat::AV_PIX_FMT_NONE || frame.width <= 0 || frame.height <= 0 {
+            return Err(invalid("decoded frame has no pixel format or size"));
+        }
+        let (width, height) = (frame.width as usize, frame.height as usize);
+        let accepted = match self.scaler {
+            Some(ref scaler) => scaler.accepts(width, height, format),
+            None => false,
+        };
+        if !accepted {
+            self.scaler = Some(Scaler::new(
+                width,
+                height,
+                format,
+                self.picture.width,
+                self.picture.height,
+            )?);
+        }
+        if let Some(ref mut scaler) = self.scaler {
+            scaler.scale_frame(frame, &mut self.picture)?;
+        }
+        self.shown = true;
+        Ok(())
+    }
+}
+
+impl FrameSource for PlaybackSource {
+    fn size(&self) -> (usize, usize) {
+        (self.picture.width, self.picture.height)
+    }
+
+    fn frame(&mut self, timestamp_ms: u64) -> Result<Option<Frame>, MediaError> {
+        loop {
+            if let Some(pending_ms) = self.pending_ms {
+                if self.shown && pending_ms > timestamp_ms {
+                    break;
+                }
+                unsafe { self.show_pending()? };
+                self.pending_ms = None;
+            }
+            match unsafe { self.decode()? } {
+                Some(ms) => self.pending_ms = Some(ms),
+                None => {
+                    if unsafe { self.rewind()? } {
+                        continue;
+                    }
+                    // The last frame is shown for one more frame interval
+                    if self.shown && timestamp_ms < self.last_ms + self.frame_interval_ms {
+                        break;
+                    }
+                    return Ok(None);
+                }
+            }
+        }
+        self.picture.timestamp_ms = timestamp_ms;
+        Ok(Some(Frame::I420(&self.picture)))
+    }
+}
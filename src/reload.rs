@@ -0,0 +1,116 @@
+use arc_swap::ArcSwap;
+use camcoder::Command;
+use config::{AdminConfig, Config};
+use log;
+use logger;
+use cors::CorsPolicy;
+use renditions::Renditions;
+use std::sync::Arc;
+use std::sync::mpsc::Sender;
+
+// Re-reads the configuration on SIGHUP and applies what can be changed while
+// running. Settings that need a restart keep their current value.
+pub struct Reloader {
+    config: Config,
+    renditions: Arc<Renditions>,
+    cors_policy: Arc<ArcSwap<CorsPolicy>>,
+    admin_config: Arc<ArcSwap<AdminConfig>>,
+    // The camcoder thread owns the encoder
+    camcoder_commands: Sender<Command>,
+}
+
+impl Reloader {
+    pub fn new(
+        config: Config,
+        renditions: Arc<Renditions>,
+        cors_policy: Arc<ArcSwap<CorsPolicy>>,
+        admin_config: Arc<ArcSwap<AdminConfig>>,
+        camcoder_commands: Sender<Command>,
+    ) -> Reloader {
+        Reloader {
+            config,
+            renditions,
+            cors_policy,
+            admin_config,
+            camcoder_commands,
+        }
+    }
+
+    pub fn reload(&mut self) {
+        let mut config = match Config::from_args() {
+            Ok(config) => config,
+            Err(err) => {
+                warn!("Ignored reload: {}", err);
+                return;
+            }
+        };
+
+        if config.log.format != self.config.log.format {
+            warn!("Ignored log.format, it needs a restart");
+            config.log.format = self.config.log.format;
+        }
+        if config.server != self.config.server {
+            warn!("Ignored server settings, they need a restart");
+            config.server = self.config.server.clone();
+        }
+        if config.health != self.config.health {
+            warn!("Ignored health settings, they need a restart");
+            config.health = self.config.health.clone();
+        }
+        if config.supervisor != self.config.supervisor {
+            warn!("Ignored supervisor settings, they need a restart");
+            config.supervisor = self.config.supervisor.clone();
+        }
+        if config.source != self.config.source {
+            warn!("Ignored source settings, they need a restart");
+            config.source = self.config.source.clone();
+        }
+        if config.segmenter != self.config.segmenter {
+            warn!("Ignored segmenter settings, they need a restart");
+            config.segmenter = self.config.segmenter.clone();
+        }
+        if config.encoder.width != self.config.encoder.width
+            || config.encoder.height != self.config.encoder.height
+            || config.encoder.frame_duration_ms != self.config.encoder.frame_duration_ms
+        {
+            warn!("Ignored encoder.width, height and frame_duration_ms, they need a restart");
+            config.encoder.width = self.config.encoder.width;
+            config.encoder.height = self.config.encoder.height;
+            config.encoder.frame_duration_ms = self.config.encoder.frame_duration_ms;
+        }
+
+        if config.log.level != self.config.log.level {
+            log::set_max_level(logger::level_filter(&config.log.level));
+            info!("Applied log.level = {}", config.log.level);
+        }
+        if config.playlist != self.config.playlist {
+            for hls in self.renditions.all_hls() {
+                hls.set_playlist_options(
+                    config.playlist.window_size,
+                    config.playlist.live_edge_segments,
+                );
+            }
+            info!(
+                "Applied playlist.window_size = {}, playlist.live_edge_segments = {}",
+                config.playlist.window_size, config.playlist.live_edge_segments
+            );
+        }
+        if config.cors != self.config.cors {
+            self.cors_policy.store(Arc::new(config.cors.to_policy()));
+            info!("Applied cors settings");
+        }
+        if config.admin != self.config.admin {
+            self.admin_config.store(Arc::new(config.admin.clone()));
+            info!("Applied admin settings");
+        }
+        if config.encoder != self.config.encoder || config.overlay != self.config.overlay {
+            let command = Command::Reconfigure(config.encoder.clone(), config.overlay.clone());
+            if self.camcoder_commands.send(command).is_err() {
+                warn!("Ignored encoder and overlay settings, the camcoder has stopped");
+                config.encoder = self.config.encoder.clone();
+                config.overlay = self.config.overlay.clone();
+            }
+        }
+        self.config = config;
+    }
+}
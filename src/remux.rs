@@ -0,0 +1,283 @@
+use audio::AudioTracks;
+use camcoder::{Command, Pipeline};
+use chrono::prelude::*;
+use demux::{AccessUnit, Demuxer};
+use error::MediaError;
+use h264;
+use lazybytes::LazyBytes;
+use metrics::Metrics;
+use mpegts::MpegTs;
+use renditions::{InputVideo, Renditions};
+use std::sync::Arc;
+use std::time::Instant;
+
+// Segments pre-encoded H.264 without decoding or encoding it. Access units
+// are written once their DTS is reached on the frame ticks. Segments are cut
+// at the first IDR picture at least the segment duration after the previous
+// cut, so their durations follow the GOP structure of the input. They are
+// published once cut, with the duration they turned out to have, which may
+// not exceed the target duration. Audio starts along with the first IDR
+// picture and is cut at the same ticks as the video.
+pub struct Remuxer {
+    renditions: Arc<Renditions>,
+    metrics: Arc<Metrics>,
+    demuxer: Demuxer,
+    // Read ahead of its time
+    pending: Option<AccessUnit>,
+    ended: bool,
+    width: usize,
+    height: usize,
+    frame_duration_ms: u64,
+    current_ms: u64,
+    ts_duration_ms: u64,
+    // DTS of the IDR picture the video segment starts with, None until the
+    // first one arrives
+    segment_start_ms: Option<u64>,
+    segment_end_ms: u64,
+    // Of the playlists, see Hls::start_generation
+    generation: u64,
+    // Of the next video segment, the first one this remuxer publishes
+    discontinuity: bool,
+    dropped_access_units: u64,
+    audio_segment_start_ms: u64,
+    mpeg_ts: MpegTs,
+    // Of the video segment in progress
+    lazy_bytes: Arc<LazyBytes>,
+    // Announced in the multivariant playlist
+    input_video: InputVideo,
+    bandwidth_measured: bool,
+    audio_tracks: AudioTracks,
+}
+
+impl Remuxer {
+    pub fn new(
+        renditions: Arc<Renditions>,
+        metrics: Arc<Metrics>,
+        demuxer: Demuxer,
+        frame_duration_ms: u64,
+        ts_duration_ms: u64,
+        generation: u64,
+        discontinuity: bool,
+    ) -> Result<Remuxer, MediaError> {
+        let width = renditions.video.width;
+        let height = renditions.video.height;
+
+        let audio_tracks = AudioTracks::new(&renditions, generation)?;
+        let lazy_bytes = LazyBytes::new();
+        let video_stream = Some((width, height, demuxer.profile_level()));
+        let mpeg_ts = MpegTs::new(video_stream, None, lazy_bytes.clone())?;
+
+        // The configured bitrate stands in until a segment is measured
+        let input_video = InputVideo {
+            profile_level: demuxer.profile_level(),
+            bandwidth: renditions.video.encoder.load().bitrate,
+        };
+        renditions.publish_input_video(input_video);
+
+        Ok(Remuxer {
+            renditions,
+            metrics,
+            demuxer,
+            pending: None,
+            ended: false,
+            width,
+            height,
+            frame_duration_ms,
+            current_ms: 0,
+            ts_duration_ms,
+            segment_start_ms: None,
+            segment_end_ms: 0,
+            generation,
+            discontinuity,
+            dropped_access_units: 0,
+            audio_segment_start_ms: 0,
+            mpeg_ts,
+            lazy_bytes,
+            input_video,
+            bandwidth_measured: false,
+            audio_tracks,
+        })
+    }
+
+    fn next_access_unit(&mut self) -> Result<Option<AccessUnit>, MediaError> {
+        if let Some(access_unit) = self.pending.take() {
+            return Ok(Some(access_unit));
+        }
+        if self.ended {
+            return Ok(None);
+        }
+        let read_started_at = Instant::now();
+        let access_unit = self.demuxer.access_unit()?;
+        self.metrics
+            .frame_render_seconds
+            .observe_duration(read_started_at.elapsed());
+        self.ended = access_unit.is_none();
+        Ok(access_unit)
+    }
+
+    // Writes an access unit into the video segment. Returns whether it
+    // started a new segment.
+    fn write(&mut self, mut access_unit: AccessUnit) -> Result<bool, MediaError> {
+        // Keyframes are told by their NAL units, whatever the container says
+        let key = h264::is_idr(&access_unit.data);
+        let segment_start_ms = match self.segment_start_ms {
+            Some(segment_start_ms) => segment_start_ms,
+            // A segment has to start with an IDR picture to be decodable
+            None if !key => {
+                self.dropped_access_units += 1;
+                return Ok(false);
+            }
+            None => {
+                if self.dropped_access_units > 0 {
+                    info!(
+                        "Dropped {} access units before the first IDR picture",
+                        self.dropped_access_units
+                    );
+                }
+                access_unit.dts_ms
+            }
+        };
+
+        let cut = key && access_unit.dts_ms >= segment_start_ms + self.ts_duration_ms;
+        if cut {
+            self.cut_video(access_unit.dts_ms - segment_start_ms)?;
+            self.segment_start_ms = Some(access_unit.dts_ms);
+        } else {
+            self.segment_start_ms = Some(segment_start_ms);
+        }
+
+        self.metrics.encoded_frames.inc();
+        if let Some(label) = h264::frame_label(&access_unit.data) {
+            self.metrics
+                .frame_bytes(label)
+                .observe(access_unit.data.len());
+        }
+        self.mpeg_ts.write(
+            &mut access_unit.data,
+            access_unit.pts_ms,
+            access_unit.dts_ms,
+            access_unit.duration_ms,
+            key,
+        )?;
+        self.segment_end_ms = access_unit.dts_ms + access_unit.duration_ms;
+        Ok(cut)
+    }
+
+    fn cut_video(&mut self, duration_ms: u64) -> Result<(), MediaError> {
+        self.mpeg_ts.flush()?;
+        self.publish_video(duration_ms)?;
+        self.lazy_bytes = LazyBytes::new();
+        let video_stream = Some((self.width, self.height, self.demuxer.profile_level()));
+        self.mpeg_ts = MpegTs::new(video_stream, None, self.lazy_bytes.clone())?;
+        Ok(())
+    }
+
+    // Adds the flushed video segment to the playlist. The first one checks
+    // that the keyframe interval of the input fits the target duration before
+    // anything is published.
+    fn publish_video(&mut self, duration_ms: u64) -> Result<(), MediaError> {
+        let video = &self.renditions.video;
+        let target_duration = video.hls.snapshot().target_duration();
+        // EXTINF rounded to the nearest second may not exceed it
+        if (duration_ms + 500) / 1000 > target_duration {
+            return Err(MediaError::Unsupported(format!(
+                "segment of {} ms cut at the keyframes of the input is longer than the \
+                 target duration of {} s, raise segmenter.segment_duration_ms",
+                duration_ms, target_duration
+            )));
+        }
+        let written_bytes = self.mpeg_ts.written_bytes();
+        self.metrics
+            .observe_segment(&video.name, written_bytes, duration_ms);
+        video.hls.add_new_segment(
+            self.generation,
+            duration_ms,
+            self.lazy_bytes.clone(),
+            self.discontinuity,
+        );
+        self.discontinuity = false;
+
+        let bandwidth = written_bytes as u64 * 8 * 1000 / duration_ms;
+        if bandwidth > self.input_video.bandwidth || !self.bandwidth_measured {
+            self.input_video.bandwidth = bandwidth;
+            self.bandwidth_measured = true;
+            self.renditions.publish_input_video(self.input_video);
+        }
+        Ok(())
+    }
+}
+
+impl Pipeline for Remuxer {
+    // Writes the access units due by the end of this tick
+    fn run(&mut self) -> Result<bool, MediaError> {
+        let now = Local::now();
+        let start_ms = self.current_ms;
+        self.current_ms += self.frame_duration_ms;
+
+        let started = self.segment_start_ms.is_some();
+        // Audio is published after the video segment of this tick
+        let discontinuity = self.discontinuity;
+        let mut cut = false;
+        while let Some(access_unit) = self.next_access_unit()? {
+            if access_unit.dts_ms >= self.current_ms {
+                self.pending = Some(access_unit);
+                break;
+            }
+            if self.write(access_unit)? {
+                cut = true;
+            }
+        }
+
+        if self.segment_start_ms.is_none() {
+            return Ok(!self.ended);
+        }
+        // Audio before the first IDR picture would have no video to go with
+        if !started {
+            self.audio_tracks.start_at(start_ms);
+            self.audio_segment_start_ms = start_ms;
+        }
+        // Audio follows the video cut at the start of this tick, less than a
+        // frame duration away from it
+        if cut {
+            let duration_ms = start_ms - self.audio_segment_start_ms;
+            self.audio_tracks
+                .publish(&self.renditions, duration_ms, discontinuity);
+            self.audio_tracks
+                .cut(&self.renditions, &self.metrics, duration_ms)?;
+            self.audio_segment_start_ms = start_ms;
+        }
+        let wall_clock_ms = (now.nanosecond() / 1_000_000) as u64;
+        self.audio_tracks.write(start_ms, self.current_ms, wall_clock_ms)?;
+        Ok(!self.ended)
+    }
+
+    // Encoder settings do not apply to video that is not encoded here
+    fn handle(&mut self, command: Command) -> Result<(), MediaError> {
+        match command {
+            Command::Reconfigure(..) => {}
+            Command::SetBitrate(_) | Command::SetMaxFrameRate(_) | Command::ForceIntraFrame => {
+                warn!("Ignored an encoder command, passthrough video is not encoded");
+            }
+        }
+        Ok(())
+    }
+
+    // Publishes the segments in progress unless nothing was written
+    fn finish(&mut self) -> Result<(), MediaError> {
+        let discontinuity = self.discontinuity;
+        let mut result = self.mpeg_ts.flush();
+        if let Some(segment_start_ms) = self.segment_start_ms {
+            let video_duration_ms = self.segment_end_ms - segment_start_ms;
+            if result.is_ok() && video_duration_ms > 0 {
+                result = self.publish_video(video_duration_ms);
+            }
+            let audio_duration_ms = self.current_ms - self.audio_segment_start_ms;
+            if audio_duration_ms > 0 {
+                self.audio_tracks
+                    .publish(&self.renditions, audio_duration_ms, discontinuity);
+            }
+        }
+        self.renditions.video.hls.end(self.generation, None);
+        result.and(self.audio_tracks.finish(&self.renditions, None))
+    }
+}
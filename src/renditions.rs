@@ -0,0 +1,186 @@
+use arc_swap::ArcSwap;
+use hls::{Hls, RenderedPlaylist};
+use std::sync::Arc;
+use config::EncoderConfig;
+use h264::ProfileLevel;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+
+const AUDIO_GROUP_ID: &str = "audio";
+const AUDIO_CODEC: &str = "mp4a.40.2";
+
+pub struct VideoRendition {
+    pub name: String,
+    pub width: usize,
+    pub height: usize,
+    // Settings of the running encoder, published by the camcoder
+    pub encoder: ArcSwap<EncoderConfig>,
+    pub hls: Arc<Hls>,
+}
+
+// Passthrough video, which the multivariant playlist announces instead of
+// the encoder settings
+#[derive(Clone, Copy, PartialEq)]
+pub struct InputVideo {
+    pub profile_level: ProfileLevel,
+    // Peak bitrate of the segments so far
+    pub bandwidth: u64,
+}
+
+pub struct AudioRendition {
+    pub name: String,
+    pub display_name: String,
+    pub language: String,
+    pub tone_hz: f64,
+    pub bandwidth: u64,
+    pub hls: Arc<Hls>,
+}
+
+pub struct Renditions {
+    pub video: VideoRendition,
+    pub audios: Vec<AudioRendition>,
+    input_video: ArcSwap<Option<InputVideo>>,
+    // Rendered again only when the video settings it announces change
+    multivariant_playlist: ArcSwap<RenderedPlaylist>,
+}
+
+impl Renditions {
+    pub fn new(video: VideoRendition, audios: Vec<AudioRendition>) -> Renditions {
+        let playlist = render_multivariant_playlist(&video, &audios, None);
+        Renditions {
+            video,
+            audios,
+            input_video: ArcSwap::from(Arc::new(None)),
+            multivariant_playlist: ArcSwap::from(Arc::new(playlist)),
+        }
+    }
+
+    pub fn find_hls(&self, name: &str) -> Option<Arc<Hls>> {
+        if self.video.name == name {
+            return Some(self.video.hls.clone());
+        }
+        self.audios
+            .iter()
+            .find(|audio| audio.name == name)
+            .map(|audio| audio.hls.clone())
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        let mut names = vec![self.video.name.clone()];
+        names.extend(self.audios.iter().map(|audio| audio.name.clone()));
+        names
+    }
+
+    pub fn all_hls(&self) -> Vec<Arc<Hls>> {
+        let mut all_hls = vec![self.video.hls.clone()];
+        all_hls.extend(self.audios.iter().map(|audio| audio.hls.clone()));
+        all_hls
+    }
+
+    // Starts a producer of every rendition, see Hls::start_generation
+    pub fn start_generation(&self) -> u64 {
+        let generation = self.video.hls.start_generation();
+        for audio in &self.audios {
+            audio.hls.start_generation();
+        }
+        generation
+    }
+
+    pub fn multivariant_playlist(&self) -> Arc<RenderedPlaylist> {
+        self.multivariant_playlist.load()
+    }
+
+    // Publishes the settings of the running encoder
+    pub fn publish_encoder(&self, encoder: EncoderConfig) {
+        self.video.encoder.store(Arc::new(encoder));
+        self.render_multivariant_playlist();
+    }
+
+    pub fn input_video(&self) -> Option<InputVideo> {
+        *self.input_video.load()
+    }
+
+    // Publishes the format of passthrough video
+    pub fn publish_input_video(&self, input_video: InputVideo) {
+        if self.input_video() == Some(input_video) {
+            return;
+        }
+        self.input_video.store(Arc::new(Some(input_video)));
+        self.render_multivariant_playlist();
+    }
+
+    fn render_multivariant_playlist(&self) {
+        let playlist =
+            render_multivariant_playlist(&self.video, &self.audios, self.input_video());
+        self.multivariant_playlist.store(Arc::new(playlist));
+    }
+}
+
+fn render_multivariant_playlist(
+    video: &VideoRendition,
+    audios: &[AudioRendition],
+    input_video: Option<InputVideo>,
+) -> RenderedPlaylist {
+    let playlist = generate_multivariant_playlist(video, audios, input_video);
+    let mut hasher = DefaultHasher::new();
+    hasher.write(playlist.as_bytes());
+    let etag = format!("{:016x}", hasher.finish());
+    RenderedPlaylist::new(playlist, etag)
+}
+
+fn generate_multivariant_playlist(
+    video: &VideoRendition,
+    audios: &[AudioRendition],
+    input_video: Option<InputVideo>,
+) -> String {
+    let mut playlist = String::from("#EXTM3U\n#EXT-X-VERSION:6\n#EXT-X-INDEPENDENT-SEGMENTS\n\n");
+    for (i, audio) in audios.iter().enumerate() {
+        let default = if i == 0 { "YES" } else { "NO" };
+        playlist.push_str(&format!(
+            "#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"{}\",NAME=\"{}\",LANGUAGE=\"{}\",DEFAULT={},AUTOSELECT=YES,URI=\"{}/index.m3u8\"\n",
+            AUDIO_GROUP_ID, audio.display_name, audio.language, default, audio.name
+        ));
+    }
+    playlist.push('\n');
+
+    let audio_bandwidth = audios
+        .iter()
+        .map(|audio| audio.bandwidth)
+        .max()
+        .unwrap_or(0);
+    let audio_group = if audios.is_empty() {
+        String::new()
+    } else {
+        format!(",AUDIO=\"{}\"", AUDIO_GROUP_ID)
+    };
+    let (video_codec, video_bandwidth) = match input_video {
+        Some(input_video) => (input_video.profile_level.codec(), input_video.bandwidth),
+        None => {
+            let encoder = video.encoder.load();
+            (encoder.profile.profile_level().codec(), encoder.bitrate)
+        }
+    };
+    let codecs = if audios.is_empty() {
+        video_codec
+    } else {
+        format!("{},{}", video_codec, AUDIO_CODEC)
+    };
+    playlist.push_str(&format!(
+        "#EXT-X-STREAM-INF:BANDWIDTH={},CODECS=\"{}\",RESOLUTION={}x{}{}\n{}/index.m3u8\n",
+        video_bandwidth + audio_bandwidth,
+        codecs,
+        video.width,
+        video.height,
+        audio_group,
+        video.name
+    ));
+
+    // Audio only variant for low bandwidth clients
+    if let Some(audio) = audios.first() {
+        playlist.push_str(&format!(
+            "#EXT-X-STREAM-INF:BANDWIDTH={},CODECS=\"{}\"{}\n{}/index.m3u8\n",
+            audio_bandwidth, AUDIO_CODEC, audio_group, audio.name
+        ));
+    }
+    playlist
+}
@@ -0,0 +1,163 @@
+use encoder::Picture;
+use error::MediaError;
+use ffmpeg_sys::{sws_freeContext, sws_getContext, sws_scale, AVFrame, AVPixelFormat, SwsContext,
+                 SWS_FAST_BILINEAR};
+use std::os::raw::c_int;
+use std::ptr::{null, null_mut};
+
+// Converts images of one size and pixel format to I420 pictures, scaling
+// them when the sizes differ. The context is freed on drop.
+pub struct Scaler {
+    context: *mut SwsContext,
+    src_width: usize,
+    src_height: usize,
+    src_format: AVPixelFormat,
+    width: usize,
+    height: usize,
+}
+
+impl Scaler {
+    pub fn new(
+        src_width: usize,
+        src_height: usize,
+        src_format: AVPixelFormat,
+        width: usize,
+        height: usize,
+    ) -> Result<Scaler, MediaError> {
+        let context = unsafe {
+            sws_getContext(
+                src_width as i32,
+                src_height as i32,
+                src_format,
+                width as i32,
+                height as i32,
+                AVPixelFormat::AV_PIX_FMT_YUV420P,
+                SWS_FAST_BILINEAR,
+                null_mut(),
+                null_mut(),
+                null_mut(),
+            )
+        };
+        if context.is_null() {
+            return Err(MediaError::Null("sws_getContext"));
+        }
+        Ok(Scaler {
+            context,
+            src_width,
+            src_height,
+            src_format,
+            width,
+            height,
+        })
+    }
+
+    pub fn rgb24_to_i420(width: usize, height: usize) -> Result<Scaler, MediaError> {
+        Scaler::new(width, height, AVPixelFormat::AV_PIX_FMT_RGB24, width, height)
+    }
+
+    // Whether the scaler converts images of this size and format
+    pub fn accepts(&self, width: usize, height: usize, format: AVPixelFormat) -> bool {
+        self.src_width == width && self.src_height == height && self.src_format == format
+    }
+
+    // Rows of rgb are stride bytes apart
+    pub fn scale(
+        &mut self,
+        rgb: &[u8],
+        stride: usize,
+        picture: &mut Picture,
+    ) -> Result<(), MediaError> {
+        let row_bytes = self.src_width * 3;
+        if stride < row_bytes || rgb.len() < stride * (self.src_height - 1) + row_bytes {
+            return Err(MediaError::InvalidInput(format!(
+                "image of {} bytes with a stride of {} for a scaler of {}x{}",
+                rgb.len(),
+                stride,
+                self.src_width,
+                self.src_height
+            )));
+        }
+        self.check_picture(picture)?;
+        if self.src_format != AVPixelFormat::AV_PIX_FMT_RGB24 {
+            return Err(MediaError::InvalidInput(format!(
+                "RGB24 image for a scaler of {:?}",
+                self.src_format
+            )));
+        }
+        let src: [*const u8; 4] = [rgb.as_ptr(), null(), null(), null()];
+        let src_strides: [c_int; 4] = [stride as i32, 0, 0, 0];
+        unsafe { self.scale_planes(src.as_ptr(), src_strides.as_ptr(), picture) }
+    }
+
+    // The frame must be a decoded frame of the size and format the scaler
+    // accepts, so that its planes hold every row sws_scale reads.
+    pub unsafe fn scale_frame(
+        &mut self,
+        frame: &AVFrame,
+        picture: &mut Picture,
+    ) -> Result<(), MediaError> {
+        if frame.width as usize != self.src_width || frame.height as usize != self.src_height {
+            return Err(MediaError::InvalidInput(format!(
+                "frame of {}x{} for a scaler of {}x{}",
+                frame.width, frame.height, self.src_width, self.src_height
+            )));
+        }
+        self.check_picture(picture)?;
+        self.scale_planes(
+            frame.data.as_ptr() as *const *const u8,
+            frame.linesize.as_ptr(),
+            picture,
+        )
+    }
+
+    fn check_picture(&self, picture: &Picture) -> Result<(), MediaError> {
+        if picture.width != self.width || picture.height != self.height
+            || !picture.is_consistent()
+        {
+            return Err(MediaError::InvalidInput(format!(
+                "picture of {}x{} for a scaler of {}x{}",
+                picture.width, picture.height, self.width, self.height
+            )));
+        }
+        Ok(())
+    }
+
+    unsafe fn scale_planes(
+        &mut self,
+        src: *const *const u8,
+        src_strides: *const c_int,
+        picture: &mut Picture,
+    ) -> Result<(), MediaError> {
+        let dst: [*const u8; 4] = [
+            picture.planes[0].as_mut_ptr(),
+            picture.planes[1].as_mut_ptr(),
+            picture.planes[2].as_mut_ptr(),
+            null(),
+        ];
+        let dst_strides: [c_int; 4] = [
+            picture.strides[0] as i32,
+            picture.strides[1] as i32,
+            picture.strides[2] as i32,
+            0,
+        ];
+        if sws_scale(
+            self.context,
+            src,
+            src_strides,
+            0,
+            self.src_height as i32,
+            dst.as_ptr(),
+            dst_strides.as_ptr(),
+        ) == 0
+        {
+            return Err(MediaError::Failed("sws_scale"));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Scaler {
+    fn drop(&mut self) {
+        unsafe { sws_freeContext(self.context) };
+    }
+}
@@ -1,30 +1,456 @@
 use futures;
-use hyper::{Body, Request, Response, Server};
-use hyper::rt::Future;
-use hyper::service::service_fn_ok;
-use hyper::{Get, StatusCode};
-use hyper::header::{ContentLength, ContentType, Location};
+use futures::Future;
+use hyper;
+use hyper::{Get, Head, Method, Post, Put, StatusCode};
+use hyper::header::{AcceptRanges, Allow, CacheControl, CacheDirective, ContentEncoding, ContentLength,
+                    ContentRange, ContentRangeSpec, ContentType, ETag, EntityTag, HttpDate, IfModifiedSince,
+                    IfNoneMatch, LastModified, Location, Range, RangeUnit};
 use hyper::server::{Request, Response, Service};
-use hls::Hls;
-use std::sync::{Arc, RwLock};
+use renditions::Renditions;
+use cors::CorsPolicy;
+use arc_swap::ArcSwap;
+use compression::ContentCoding;
+use std::sync::Arc;
+use std::rc::Rc;
 use std::path::PathBuf;
 use std::fs::{canonicalize, File};
 use std::error::Error;
 use std::io::copy;
 use lazybytes::LazyBytesStream;
+use hls::SEGMENT_PREFIX;
+use metrics::{ActiveSegmentStream, Metrics};
+use accesslog::{AccessLog, Body};
+use health::Health;
+use status;
+use camcoder::Command;
+use config::{AdminConfig, EncoderConfig};
+use serde_json;
+use std::sync::mpsc::Sender;
 use futures::Stream;
-use futures::stream::once;
+use futures::stream::iter_ok;
+use bytes::Bytes;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-type Body = Box<Stream<Item = hyper::Chunk, Error = hyper::Error>>;
+// Playlists change every segment, so let caches keep them only briefly.
+const PLAYLIST_MAX_AGE_SECONDS: u32 = 1;
+const COMPLETED_SEGMENT_MAX_AGE_SECONDS: u32 = 365 * 24 * 60 * 60;
 
 pub struct HlsService {
-    hls: Arc<RwLock<Hls>>,
+    renditions: Arc<Renditions>,
+    // Replaced when the configuration is reloaded
+    cors_policy: Arc<ArcSwap<CorsPolicy>>,
+    metrics: Arc<Metrics>,
+    health: Arc<Health>,
+    admin_config: Arc<ArcSwap<AdminConfig>>,
+    camcoder_commands: Sender<Command>,
 }
 
 impl HlsService {
-    pub fn new(hls: Arc<RwLock<Hls>>) -> HlsService {
-        HlsService { hls }
+    pub fn new(
+        renditions: Arc<Renditions>,
+        cors_policy: Arc<ArcSwap<CorsPolicy>>,
+        metrics: Arc<Metrics>,
+        health: Arc<Health>,
+        admin_config: Arc<ArcSwap<AdminConfig>>,
+        camcoder_commands: Sender<Command>,
+    ) -> HlsService {
+        HlsService {
+            renditions,
+            cors_policy,
+            metrics,
+            health,
+            admin_config,
+            camcoder_commands,
+        }
     }
+
+    fn finish(
+        &self,
+        access_log: Rc<AccessLog>,
+        response: Response<Body>,
+    ) -> Box<Future<Item = Response<Body>, Error = hyper::Error>> {
+        record_response(&self.metrics, access_log, &response);
+        Box::new(futures::future::ok(response))
+    }
+
+    // Runtime encoder control. Changes are applied by the camcoder thread at
+    // its next tick.
+    fn admin(
+        &self,
+        mut req: Request,
+        access_log: Rc<AccessLog>,
+        cors_policy: Arc<CorsPolicy>,
+    ) -> Box<Future<Item = Response<Body>, Error = hyper::Error>> {
+        let admin_config = self.admin_config.load();
+        if admin_config.token.is_empty() {
+            let response = Response::new().with_status(StatusCode::NotFound);
+            return self.finish(access_log, cors_policy.apply(&req, response));
+        }
+        if !is_authorized(&req, &admin_config.token) {
+            let mut response = uncached_response(
+                &req,
+                StatusCode::Unauthorized,
+                "text/plain",
+                ContentCoding::Identity,
+                b"unauthorized\n",
+            );
+            response.headers_mut().set_raw("WWW-Authenticate", "Bearer");
+            return self.finish(access_log, cors_policy.apply(&req, response));
+        }
+        let method = req.method().clone();
+        let path = req.path().to_owned();
+        let response = match (method, path.as_str()) {
+            (Post, "/api/encoder/keyframe") => {
+                send_commands(&req, &self.camcoder_commands, vec![Command::ForceIntraFrame])
+            }
+            (Put, "/api/encoder") => {
+                let body = req.body_mut().take().unwrap_or_default();
+                let camcoder_commands = self.camcoder_commands.clone();
+                let encoder = self.renditions.video.encoder.load();
+                let metrics = self.metrics.clone();
+                return Box::new(body.concat2().map(move |body| {
+                    let response = match encoder_commands(&body, &encoder) {
+                        Ok(commands) => send_commands(&req, &camcoder_commands, commands),
+                        Err(message) => uncached_response(
+                            &req,
+                            StatusCode::BadRequest,
+                            "text/plain",
+                            ContentCoding::Identity,
+                            format!("{}\n", message).as_bytes(),
+                        ),
+                    };
+                    let response = cors_policy.apply(&req, response);
+                    record_response(&metrics, access_log, &response);
+                    response
+                }));
+            }
+            (_, "/api/encoder/keyframe") => Response::new()
+                .with_status(StatusCode::MethodNotAllowed)
+                .with_header(Allow(vec![Post])),
+            (_, "/api/encoder") => Response::new()
+                .with_status(StatusCode::MethodNotAllowed)
+                .with_header(Allow(vec![Put])),
+            _ => Response::new().with_status(StatusCode::NotFound),
+        };
+        self.finish(access_log, cors_policy.apply(&req, response))
+    }
+}
+
+fn record_response(metrics: &Metrics, access_log: Rc<AccessLog>, response: &Response<Body>) {
+    let status = response.status().as_u16();
+    metrics.count_response(status);
+    access_log.respond(status);
+    if let Some(body) = response.body_ref() {
+        body.attach(access_log);
+    }
+}
+
+fn is_authorized(req: &Request, token: &str) -> bool {
+    let expected = format!("Bearer {}", token);
+    match req.headers()
+        .get_raw("Authorization")
+        .and_then(|authorization| authorization.one())
+    {
+        // Compares every byte so that the time taken does not reveal the token
+        Some(actual) if actual.len() == expected.len() => actual
+            .iter()
+            .zip(expected.as_bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b)) == 0,
+        _ => false,
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EncoderChange {
+    bitrate: Option<u64>,
+    max_frame_rate: Option<f32>,
+}
+
+// Parses a PUT /api/encoder body such as {"bitrate": 2000000}
+fn encoder_commands(body: &[u8], encoder: &EncoderConfig) -> Result<Vec<Command>, String> {
+    let change: EncoderChange =
+        serde_json::from_slice(body).map_err(|err| format!("Invalid request body: {}", err))?;
+    let mut commands = Vec::new();
+    if let Some(bitrate) = change.bitrate {
+        EncoderConfig::validate_bitrate(bitrate)?;
+        commands.push(Command::SetBitrate(bitrate));
+    }
+    if let Some(max_frame_rate) = change.max_frame_rate {
+        encoder.validate_max_frame_rate(max_frame_rate)?;
+        commands.push(Command::SetMaxFrameRate(max_frame_rate));
+    }
+    if commands.is_empty() {
+        return Err("Specify bitrate or max_frame_rate".to_owned());
+    }
+    Ok(commands)
+}
+
+fn send_commands(
+    req: &Request,
+    camcoder_commands: &Sender<Command>,
+    commands: Vec<Command>,
+) -> Response<Body> {
+    for command in commands {
+        if camcoder_commands.send(command).is_err() {
+            return uncached_response(
+                req,
+                StatusCode::ServiceUnavailable,
+                "text/plain",
+                ContentCoding::Identity,
+                b"camcoder is not running\n",
+            );
+        }
+    }
+    uncached_response(
+        req,
+        StatusCode::Accepted,
+        "text/plain",
+        ContentCoding::Identity,
+        b"accepted\n",
+    )
+}
+
+// Label of the served bytes metric for a request path
+fn route_name(path: &str) -> &'static str {
+    match split_rendition_path(path) {
+        Some((_, "index.m3u8")) => "playlist",
+        Some((_, file)) if file.starts_with(SEGMENT_PREFIX) => "segment",
+        _ if path == "/index.m3u8" => "multivariant",
+        _ if path == "/metrics" => "metrics",
+        _ if path.starts_with("/api/") => "api",
+        _ if path == "/healthz" || path == "/readyz" => "health",
+        _ => "static",
+    }
+}
+
+// Splits "/{rendition}/{file}" into the rendition name and the file name.
+fn split_rendition_path(path: &str) -> Option<(&str, &str)> {
+    let mut components = path.trim_left_matches('/').splitn(2, '/');
+    match (components.next(), components.next()) {
+        (Some(name), Some(file)) if !name.is_empty() && !file.contains('/') => Some((name, file)),
+        _ => None,
+    }
+}
+
+// Returns the chunks covering the inclusive byte range [first, last].
+fn slice_chunks(chunks: &[Bytes], first: u64, last: u64) -> Vec<Bytes> {
+    let mut sliced = Vec::new();
+    let mut offset = 0;
+    for chunk in chunks {
+        let chunk_first = offset;
+        let chunk_last = offset + chunk.len() as u64;
+        offset = chunk_last;
+        if chunk_last <= first || chunk_first > last {
+            continue;
+        }
+        let start = first.saturating_sub(chunk_first) as usize;
+        let end = ::std::cmp::min(last + 1 - chunk_first, chunk.len() as u64) as usize;
+        sliced.push(chunk.slice(start, end));
+    }
+    sliced
+}
+
+// Cache policy and validators of an entity whose whole content is known.
+struct Cacheability {
+    cache_control: Option<CacheControl>,
+    etag: EntityTag,
+    last_modified: Option<SystemTime>,
+}
+
+impl Cacheability {
+    fn playlist(etag: EntityTag, last_modified: Option<SystemTime>) -> Cacheability {
+        Cacheability {
+            cache_control: Some(CacheControl(vec![
+                CacheDirective::Public,
+                CacheDirective::MaxAge(PLAYLIST_MAX_AGE_SECONDS),
+            ])),
+            etag,
+            last_modified,
+        }
+    }
+
+    fn completed_segment(etag: String, completed_at: SystemTime) -> Cacheability {
+        Cacheability {
+            cache_control: Some(CacheControl(vec![
+                CacheDirective::Public,
+                CacheDirective::MaxAge(COMPLETED_SEGMENT_MAX_AGE_SECONDS),
+                CacheDirective::Extension("immutable".to_owned(), None),
+            ])),
+            etag: EntityTag::strong(etag),
+            last_modified: Some(completed_at),
+        }
+    }
+
+    fn static_file(content: &[u8], last_modified: Option<SystemTime>) -> Cacheability {
+        Cacheability {
+            cache_control: None,
+            etag: content_etag(content),
+            last_modified,
+        }
+    }
+
+    fn is_not_modified(&self, req: &Request) -> bool {
+        // If-Modified-Since is ignored when If-None-Match is present (RFC 7232 3.3)
+        match req.headers().get::<IfNoneMatch>() {
+            Some(&IfNoneMatch::Any) => return true,
+            Some(&IfNoneMatch::Items(ref etags)) => {
+                return etags.iter().any(|etag| etag.weak_eq(&self.etag))
+            }
+            None => {}
+        }
+        match (req.headers().get::<IfModifiedSince>(), self.last_modified) {
+            (Some(&IfModifiedSince(since)), Some(last_modified)) => {
+                HttpDate::from(truncate_to_seconds(last_modified)) <= since
+            }
+            _ => false,
+        }
+    }
+
+    fn apply(&self, response: Response<Body>) -> Response<Body> {
+        let mut response = response.with_header(ETag(self.etag.clone()));
+        if let Some(ref cache_control) = self.cache_control {
+            response.headers_mut().set(cache_control.clone());
+        }
+        if let Some(last_modified) = self.last_modified {
+            response
+                .headers_mut()
+                .set(LastModified(HttpDate::from(truncate_to_seconds(last_modified))));
+        }
+        // Every response is generated by this origin, not served from a cache
+        response.headers_mut().set_raw("Age", "0");
+        response
+    }
+}
+
+fn content_etag(content: &[u8]) -> EntityTag {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(content);
+    EntityTag::strong(format!("{:016x}", hasher.finish()))
+}
+
+// HTTP dates have a resolution of one second.
+fn truncate_to_seconds(time: SystemTime) -> SystemTime {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => UNIX_EPOCH + Duration::from_secs(duration.as_secs()),
+        Err(_) => time,
+    }
+}
+
+// Builds a response for an entity whose whole content is known, honoring
+// conditional requests, HEAD and a single byte range. Multiple ranges are
+// answered with the full entity.
+fn complete_response(
+    req: &Request,
+    chunks: Vec<Bytes>,
+    cacheability: &Cacheability,
+) -> Response<Body> {
+    if cacheability.is_not_modified(req) {
+        return cacheability.apply(Response::new().with_status(StatusCode::NotModified));
+    }
+
+    let len = chunks.iter().map(|chunk| chunk.len() as u64).sum::<u64>();
+    let range = match req.headers().get::<Range>() {
+        Some(&Range::Bytes(ref specs)) if specs.len() == 1 => {
+            match specs[0].to_satisfiable_range(len) {
+                Some(range) => Some(range),
+                None => {
+                    return Response::new()
+                        .with_header(ContentRange(ContentRangeSpec::Bytes {
+                            range: None,
+                            instance_length: Some(len),
+                        }))
+                        .with_status(StatusCode::RangeNotSatisfiable)
+                }
+            }
+        }
+        _ => None,
+    };
+
+    let mut response =
+        cacheability.apply(Response::new().with_header(AcceptRanges(vec![RangeUnit::Bytes])));
+    let chunks = match range {
+        Some((first, last)) => {
+            response = response
+                .with_status(StatusCode::PartialContent)
+                .with_header(ContentLength(last - first + 1))
+                .with_header(ContentRange(ContentRangeSpec::Bytes {
+                    range: Some((first, last)),
+                    instance_length: Some(len),
+                }));
+            slice_chunks(&chunks, first, last)
+        }
+        None => {
+            response = response.with_header(ContentLength(len));
+            chunks
+        }
+    };
+    if req.method() == &Head {
+        return response;
+    }
+    let body = Body::new(iter_ok(chunks.into_iter().map(hyper::Chunk::from)));
+    response.with_body(body)
+}
+
+// Text responses are compressed according to Accept-Encoding. The ETag is
+// computed from the encoded bytes, so every encoding has its own tag.
+fn encoded_response(
+    req: &Request,
+    content_coding: ContentCoding,
+    encoded: Bytes,
+    cacheability: &Cacheability,
+) -> Response<Body> {
+    let mut response = complete_response(req, vec![encoded], cacheability);
+    response.headers_mut().append_raw("Vary", "Accept-Encoding");
+    if let Some(encoding) = content_coding.encoding() {
+        response.headers_mut().set(ContentEncoding(vec![encoding]));
+    }
+    response
+}
+
+// Status and diagnostics responses, which must always be fresh
+fn uncached_response(
+    req: &Request,
+    status: StatusCode,
+    content_type: &str,
+    content_coding: ContentCoding,
+    body: &[u8],
+) -> Response<Body> {
+    let encoded = content_coding.encode(body);
+    let mut response = Response::new()
+        .with_status(status)
+        .with_header(ContentLength(encoded.len() as u64))
+        .with_header(CacheControl(vec![CacheDirective::NoStore]))
+        .with_header(ContentType(content_type.parse().expect(&format!(
+            "Failed to parse {} as mime",
+            content_type
+        ))));
+    response.headers_mut().append_raw("Vary", "Accept-Encoding");
+    if let Some(encoding) = content_coding.encoding() {
+        response.headers_mut().set(ContentEncoding(vec![encoding]));
+    }
+    if req.method() == &Head {
+        return response;
+    }
+    response.with_body(Body::new(iter_ok(vec![hyper::Chunk::from(encoded)])))
+}
+
+fn playlist_response(
+    req: &Request,
+    content_coding: ContentCoding,
+    playlist: Bytes,
+    etag: EntityTag,
+    last_modified: Option<SystemTime>,
+) -> Response<Body> {
+    let content_type_str = "application/vnd.apple.mpegurl";
+    let content_type = content_type_str
+        .parse()
+        .expect(&format!("Failed to parse {} as mime", content_type_str));
+    let cacheability = Cacheability::playlist(etag, last_modified);
+    encoded_response(req, content_coding, playlist, &cacheability)
+        .with_header(ContentType(content_type))
 }
 
 impl Service for HlsService {
@@ -34,25 +460,59 @@ impl Service for HlsService {
     type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
 
     fn call(&self, req: Request) -> Self::Future {
-        const SEGMENT_PREFIX: &str = "/segment";
-        Box::new(futures::future::ok(match (req.method(), req.path()) {
-            (&Get, path) if path.starts_with(SEGMENT_PREFIX) => {
-                match path.replace(SEGMENT_PREFIX, "")
-                    .replace(".ts", "")
-                    .parse::<u64>()
-                {
-                    Ok(segment_index) => match {
-                        let lock = self.hls
-                            .as_ref()
-                            .read()
-                            .expect("Failed to lock internal resource for reading hls segment");
-                        let hls = &*lock;
-                        hls.read_segment(segment_index)
-                    } {
-                        Some(segment) => {
-                            let body: Body = Box::new(LazyBytesStream::new(segment));
-                            Response::new().with_body(body)
-                        }
+        let access_log = AccessLog::new(&req);
+        let cors_policy = self.cors_policy.load();
+        if CorsPolicy::is_preflight(&req) {
+            // The admin API also takes writes
+            let methods = if req.path().starts_with("/api/") {
+                vec![Get, Head, Post, Put, Method::Options]
+            } else {
+                vec![Get, Head, Method::Options]
+            };
+            return self.finish(access_log, cors_policy.preflight_response(&req, methods));
+        }
+        if req.path().starts_with("/api/encoder") {
+            return self.admin(req, access_log, cors_policy);
+        }
+        let rendition_path = split_rendition_path(req.path());
+        let response = match (req.method(), rendition_path) {
+            (&Get, Some((name, file))) | (&Head, Some((name, file)))
+                if file.starts_with(SEGMENT_PREFIX) =>
+            {
+                let snapshot = self.renditions.find_hls(name).map(|hls| hls.snapshot());
+                let segment_index = match snapshot {
+                    Some(ref snapshot) => snapshot.segment_index(file),
+                    None => Ok(None),
+                };
+                match segment_index {
+                    Ok(segment_index) => match snapshot.and_then(|snapshot| {
+                        let segment_index = segment_index?;
+                        snapshot
+                            .read_segment(segment_index)
+                            .map(|segment| (segment, snapshot.segment_etag(segment_index)))
+                    }) {
+                        Some((segment, etag)) => match segment.completed_chunks() {
+                            Some((chunks, completed_at)) => complete_response(
+                                &req,
+                                chunks,
+                                &Cacheability::completed_segment(etag, completed_at),
+                            ),
+                            // The length of a segment being written is unknown, so ranges
+                            // are ignored and the body is streamed as it is produced.
+                            None => {
+                                let response = Response::new()
+                                    .with_header(CacheControl(vec![CacheDirective::NoStore]));
+                                if req.method() == &Head {
+                                    response
+                                } else {
+                                    let body = Body::new(ActiveSegmentStream::new(
+                                        LazyBytesStream::new(segment),
+                                        self.metrics.clone(),
+                                    ));
+                                    response.with_body(body)
+                                }
+                            }
+                        },
                         _ => Response::new().with_status(StatusCode::NotFound),
                     },
                     Err(err) => {
@@ -63,50 +523,96 @@ impl Service for HlsService {
                     }
                 }
             }
-            (&Get, "/index.m3u8") => {
-                let playlist = {
-                    let lock = self.hls
-                        .as_ref()
-                        .read()
-                        .expect("Failed to lock internal resource for reading hls playlist");
-                    let hls = &*lock;
-                    hls.generate_playlist()
+            (&Get, Some((name, "index.m3u8"))) | (&Head, Some((name, "index.m3u8"))) => {
+                match self.renditions.find_hls(name) {
+                    Some(hls) => {
+                        let content_coding = ContentCoding::negotiate(&req);
+                        let snapshot = hls.snapshot();
+                        let playlist = snapshot.playlist();
+                        playlist_response(
+                            &req,
+                            content_coding,
+                            playlist.encoded(content_coding),
+                            EntityTag::strong(format!(
+                                "{}-{}",
+                                playlist.etag,
+                                content_coding.token()
+                            )),
+                            Some(playlist.last_modified),
+                        )
+                    }
+                    None => Response::new().with_status(StatusCode::NotFound),
+                }
+            }
+            (&Get, None) | (&Head, None) if req.path() == "/index.m3u8" => {
+                let content_coding = ContentCoding::negotiate(&req);
+                let playlist = self.renditions.multivariant_playlist();
+                playlist_response(
+                    &req,
+                    content_coding,
+                    playlist.encoded(content_coding),
+                    EntityTag::strong(format!("{}-{}", playlist.etag, content_coding.token())),
+                    Some(playlist.last_modified),
+                )
+            }
+            (&Get, None) | (&Head, None) if req.path() == "/metrics" => uncached_response(
+                &req,
+                StatusCode::Ok,
+                "text/plain; version=0.0.4",
+                ContentCoding::negotiate(&req),
+                self.metrics.render().as_bytes(),
+            ),
+            (&Get, None) | (&Head, None) if req.path() == "/api/status" => uncached_response(
+                &req,
+                StatusCode::Ok,
+                "application/json",
+                ContentCoding::negotiate(&req),
+                status::render(&self.renditions, &self.metrics).as_bytes(),
+            ),
+            (&Get, None) | (&Head, None) if req.path() == "/healthz" => uncached_response(
+                &req,
+                StatusCode::Ok,
+                "text/plain",
+                ContentCoding::Identity,
+                b"ok\n",
+            ),
+            (&Get, None) | (&Head, None) if req.path() == "/readyz" => {
+                let (status, body) = match self.health.readiness() {
+                    Ok(()) => (StatusCode::Ok, "ready\n".to_owned()),
+                    Err(reason) => (StatusCode::ServiceUnavailable, format!("{}\n", reason)),
                 };
-                let content_type_str = "application/vnd.apple.mpegurl";
-                let content_type = content_type_str
-                    .parse()
-                    .expect(&format!("Failed to parse {} as mime", content_type_str));
-                let playlist_len = playlist.len();
-                let body: Body = Box::new(once(Ok(hyper::Chunk::from(playlist))));
-                Response::new()
-                    .with_header(ContentLength(playlist_len as u64))
-                    .with_header(ContentType(content_type))
-                    .with_body(body)
+                uncached_response(
+                    &req,
+                    status,
+                    "text/plain",
+                    ContentCoding::Identity,
+                    body.as_bytes(),
+                )
             }
-            (&Get, "/") => {
+            (&Get, None) | (&Head, None) if req.path() == "/" => {
                 Response::new()
                     .with_header(Location::new("/index.html?src=index.m3u8&enableStreaming=true&autoRecoverError=true&enableWorker=true&dumpfMP4=false&levelCapping=-1&defaultAudioCodec=undefined&widevineLicenseURL="))
                     .with_status(StatusCode::SeeOther)
             }
-            (&Get, file_path_str) => {
-                let file_path = PathBuf::from(file_path_str);
+            (&Get, _) | (&Head, _) => {
+                let file_path = PathBuf::from(req.path());
                 let mut path = canonicalize(PathBuf::from(file!())).expect("file!!!");
                 assert!(path.pop());
                 assert!(path.pop());
                 path.push("www");
                 path.push(file_path.file_name().expect("no file name!"));
-                // println!("static: {:?}", path);
+                debug!("static: {:?}", path);
                 match File::open(path) {
                     Ok(mut file) => {
+                        let last_modified = file.metadata()
+                            .and_then(|metadata| metadata.modified())
+                            .ok();
                         let mut buf: Vec<u8> = Vec::new();
                         match copy(&mut file, &mut buf) {
                             Ok(_) => {
-                                let buf_len = buf.len();
-                                let body: Body = Box::new(once(Ok(hyper::Chunk::from(buf))));
-                                Response::new()
-                                    .with_header(ContentLength(buf_len as u64))
-                                    .with_body(body)
-                            },
+                                let cacheability = Cacheability::static_file(&buf, last_modified);
+                                complete_response(&req, vec![Bytes::from(buf)], &cacheability)
+                            }
                             Err(_) => Response::new().with_status(StatusCode::NotFound),
                         }
                     }
@@ -114,6 +620,16 @@ impl Service for HlsService {
                 }
             }
             _ => Response::new().with_status(StatusCode::NotFound),
-        }))
+        };
+        // Streamed segments are counted as they are sent
+        if req.method() != &Head {
+            if let Some(&ContentLength(len)) = response.headers().get::<ContentLength>() {
+                self.metrics
+                    .served_bytes(route_name(req.path()))
+                    .add(len as usize);
+            }
+        }
+        self.finish(access_log, cors_policy.apply(&req, response))
     }
 }
+
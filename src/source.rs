@@ -0,0 +1,101 @@
+use clock::ClockSource;
+use config::{OverlayConfig, SourceConfig, SourceKind};
+use encoder::Picture;
+use error::MediaError;
+use playback::PlaybackSource;
+use std::fs;
+use std::os::unix::fs::FileTypeExt;
+use y4m::RawVideoSource;
+
+// A raw frame borrowed from the source that produced it.
+pub enum Frame<'a> {
+    // Packed RGB24 rows of width * 3 bytes, stride bytes apart
+    Rgb {
+        timestamp_ms: u64,
+        data: &'a [u8],
+        stride: usize,
+    },
+    // The picture carries its own timestamp
+    I420(&'a Picture),
+}
+
+// Produces the video the camcoder encodes. Sources know nothing about
+// encoding or segmenting.
+pub trait FrameSource {
+    // Width and height of every frame
+    fn size(&self) -> (usize, usize);
+
+    // Returns the frame shown from timestamp_ms on the stream timeline, which
+    // starts at 0 and advances by the frame duration, or None once the source
+    // has ended.
+    fn frame(&mut self, timestamp_ms: u64) -> Result<Option<Frame>, MediaError>;
+
+    // Sources that draw an overlay apply changed settings here.
+    fn set_overlay(&mut self, _overlay: &OverlayConfig) {}
+}
+
+pub fn open(
+    config: &SourceConfig,
+    width: usize,
+    height: usize,
+    overlay: &OverlayConfig,
+) -> Result<Box<FrameSource>, MediaError> {
+    let source: Box<FrameSource> = match config.kind {
+        SourceKind::Clock => Box::new(ClockSource::new(width, height, overlay)?),
+        SourceKind::Y4m | SourceKind::Raw => Box::new(RawVideoSource::new(config, width, height)?),
+        SourceKind::File => Box::new(PlaybackSource::new(config, width, height)?),
+        // Handled by the remuxer, there are no frames to encode
+        SourceKind::Passthrough => {
+            return Err(MediaError::InvalidInput(
+                "passthrough input has no frames to encode".to_owned(),
+            ))
+        }
+    };
+    Ok(source)
+}
+
+// Whether a source can be opened again after its pipeline failed. stdin and
+// named pipes carry on mid-stream without what the old reader had buffered,
+// and an abandoned reader may still be blocked on them.
+pub fn can_reopen(config: &SourceConfig) -> bool {
+    if config.kind == SourceKind::Clock {
+        return true;
+    }
+    if config.path == "-" || config.path.starts_with("pipe:") {
+        return false;
+    }
+    // URLs and missing files are opened again like regular files
+    fs::metadata(&config.path)
+        .map(|metadata| !metadata.file_type().is_fifo())
+        .unwrap_or(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use magick_rust::magick_wand_genesis;
+
+    fn source_config(kind: SourceKind) -> SourceConfig {
+        SourceConfig {
+            kind,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn opens_clock_of_the_size() {
+        magick_wand_genesis();
+        let config = source_config(SourceKind::Clock);
+        let source = open(&config, 64, 36, &OverlayConfig::default()).unwrap();
+        assert_eq!(source.size(), (64, 36));
+    }
+
+    #[test]
+    fn rejects_passthrough() {
+        let config = source_config(SourceKind::Passthrough);
+        match open(&config, 64, 36, &OverlayConfig::default()) {
+            Err(MediaError::InvalidInput(_)) => {}
+            _ => panic!("passthrough opened as a frame source"),
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use config::EncoderConfig;
+use hls::Hls;
+use metrics::Metrics;
+use renditions::Renditions;
+use serde_json;
+
+#[derive(Serialize)]
+struct SegmentStatus {
+    index: u64,
+    duration_ms: u64,
+    bytes: usize,
+    complete: bool,
+    // Viewers streaming the segment while it is being written
+    viewers: usize,
+}
+
+#[derive(Serialize)]
+struct StreamStatus {
+    name: String,
+    kind: &'static str,
+    media_sequence: u64,
+    target_duration: u64,
+    ended: bool,
+    segments: Vec<SegmentStatus>,
+}
+
+#[derive(Serialize)]
+struct FrameStatus {
+    encoded: usize,
+    skipped: usize,
+    invalid: usize,
+}
+
+#[derive(Serialize)]
+struct Status {
+    uptime_seconds: f64,
+    encoder: EncoderConfig,
+    frames: FrameStatus,
+    camcoder_restarts: usize,
+    streams: Vec<StreamStatus>,
+}
+
+fn stream_status(name: &str, kind: &'static str, hls: &Hls) -> StreamStatus {
+    let snapshot = hls.snapshot();
+    StreamStatus {
+        name: name.to_owned(),
+        kind,
+        media_sequence: snapshot.media_sequence(),
+        target_duration: snapshot.target_duration(),
+        ended: snapshot.is_ended(),
+        segments: snapshot
+            .segments()
+            .iter()
+            .map(|segment| {
+                let lazy_bytes = segment.lazy_bytes();
+                SegmentStatus {
+                    index: segment.index(),
+                    duration_ms: segment.duration_ms(),
+                    bytes: lazy_bytes.len(),
+                    complete: lazy_bytes.is_complete(),
+                    viewers: lazy_bytes.stream_count(),
+                }
+            })
+            .collect(),
+    }
+}
+
+// Body of GET /api/status
+pub fn render(renditions: &Renditions, metrics: &Metrics) -> String {
+    let uptime = metrics.uptime();
+    let mut streams = vec![
+        stream_status(&renditions.video.name, "video", &renditions.video.hls),
+    ];
+    streams.extend(
+        renditions
+            .audios
+            .iter()
+            .map(|audio| stream_status(&audio.name, "audio", &audio.hls)),
+    );
+    let status = Status {
+        uptime_seconds: uptime.as_secs() as f64 + uptime.subsec_nanos() as f64 / 1e9,
+        encoder: (*renditions.video.encoder.load()).clone(),
+        frames: FrameStatus {
+            encoded: metrics.encoded_frames.get(),
+            skipped: metrics.skipped_frames.get(),
+            invalid: metrics.invalid_frames.get(),
+        },
+        camcoder_restarts: metrics.camcoder_restarts.get(),
+        streams,
+    };
+    serde_json::to_string_pretty(&status).expect("Failed to serialize status")
+}
@@ -0,0 +1,337 @@
+use av;
+use camcoder::{Camcorder, Command, Pipeline};
+use config::{Config, OverlayConfig, SourceConfig, SourceKind, SupervisorConfig};
+use demux::Demuxer;
+use error::MediaError;
+use futures::{Future, Stream};
+use health::Health;
+use magick_rust::magick_wand_genesis;
+use metrics::Metrics;
+use remux::Remuxer;
+use renditions::Renditions;
+use source;
+use std::cell::Cell;
+use std::any::Any;
+use std::cmp::min;
+use std::error::Error;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio_core::reactor::{Core, Interval};
+
+const CHECK_INTERVAL_MS: u64 = 100;
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + duration.subsec_nanos() as u64 / 1_000_000
+}
+
+// A camcoder thread that panicked while holding a lock must not take the
+// next one down with it.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+fn panic_message(payload: &(Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_owned()
+    }
+}
+
+// Why a camcoder stopped short
+struct Failure {
+    reason: String,
+    // A recreated camcoder would fail the same way
+    fatal: bool,
+}
+
+impl Failure {
+    fn new(reason: String) -> Failure {
+        Failure {
+            reason,
+            fatal: false,
+        }
+    }
+}
+
+impl From<Box<Error>> for Failure {
+    fn from(err: Box<Error>) -> Failure {
+        let fatal = match err.downcast_ref::<MediaError>() {
+            Some(&MediaError::Unsupported(_)) => true,
+            _ => false,
+        };
+        Failure {
+            reason: err.to_string(),
+            fatal,
+        }
+    }
+}
+
+// State shared between the supervisor and the camcoder threads it starts.
+struct Shared {
+    renditions: Arc<Renditions>,
+    metrics: Arc<Metrics>,
+    health: Arc<Health>,
+    commands: Mutex<Receiver<Command>>,
+    // Overlay of the last reconfiguration, so that a recreated camcoder keeps it.
+    // Encoder settings are taken from the video rendition for the same reason.
+    overlay: Mutex<OverlayConfig>,
+    source: SourceConfig,
+    // False for stdin and named pipes, whose failure ends the stream
+    reopenable: bool,
+    ts_duration_ms: u64,
+    stop: Arc<AtomicBool>,
+}
+
+// Runs the camcoder in its own thread and recreates it with backoff when it
+// fails, panics or stops making progress. Segments of a recreated camcoder
+// start with a discontinuity. Sources that can't be reopened are not retried
+// after a failure and are waited for while stalled. Input the configuration
+// doesn't fit stops it for good.
+pub struct Supervisor {
+    shared: Arc<Shared>,
+    config: SupervisorConfig,
+}
+
+impl Supervisor {
+    pub fn new(
+        renditions: Arc<Renditions>,
+        metrics: Arc<Metrics>,
+        health: Arc<Health>,
+        commands: Receiver<Command>,
+        config: &Config,
+        stop: Arc<AtomicBool>,
+    ) -> Supervisor {
+        Supervisor {
+            shared: Arc::new(Shared {
+                renditions,
+                metrics,
+                health,
+                commands: Mutex::new(commands),
+                overlay: Mutex::new(config.overlay.clone()),
+                source: config.source.clone(),
+                reopenable: source::can_reopen(&config.source),
+                ts_duration_ms: config.segmenter.segment_duration_ms,
+                stop,
+            }),
+            config: config.supervisor.clone(),
+        }
+    }
+
+    // Returns once the stop flag is set or the source has ended.
+    pub fn run(self) {
+        magick_wand_genesis();
+        av::register_all();
+        let initial_backoff = Duration::from_millis(self.config.initial_backoff_ms);
+        let max_backoff = Duration::from_millis(self.config.max_backoff_ms);
+        let mut backoff = initial_backoff;
+        let mut discontinuity = false;
+        while !self.stopped() {
+            let started_at = Instant::now();
+            let failure = match self.supervise(discontinuity) {
+                Ok(()) => return,
+                Err(failure) => failure,
+            };
+            let reason = failure.reason;
+            if self.stopped() {
+                error!("Camcoder failed while stopping: {}", reason);
+                return;
+            }
+            if failure.fatal || !self.shared.reopenable {
+                // Revokes a camcoder that may still be reading the input
+                self.shared.renditions.start_generation();
+                if failure.fatal {
+                    error!("Camcoder failed: {}. Restarting won't help, stopping", reason);
+                } else {
+                    error!(
+                        "Camcoder failed: {}. {} can't be reopened, stopping",
+                        reason, self.shared.source.path
+                    );
+                }
+                return;
+            }
+            // A camcoder that ran for a while is not failing repeatedly
+            if started_at.elapsed() >= max_backoff {
+                backoff = initial_backoff;
+            }
+            self.shared.metrics.camcoder_restarts.inc();
+            error!(
+                "Camcoder failed: {}. Restarting in {} ms",
+                reason,
+                millis(backoff)
+            );
+            self.sleep(backoff);
+            backoff = min(backoff * 2, max_backoff);
+            discontinuity = true;
+        }
+    }
+
+    fn stopped(&self) -> bool {
+        self.shared.stop.load(Ordering::Relaxed)
+    }
+
+    fn sleep(&self, duration: Duration) {
+        let until = Instant::now() + duration;
+        while !self.stopped() {
+            let now = Instant::now();
+            if now >= until {
+                return;
+            }
+            thread::sleep(min(until - now, Duration::from_millis(CHECK_INTERVAL_MS)));
+        }
+    }
+
+    // Runs one camcoder until it stops. Returns why it has to be recreated.
+    fn supervise(&self, discontinuity: bool) -> Result<(), Failure> {
+        let (exit_sender, exit_receiver) = channel();
+        let abandoned = Arc::new(AtomicBool::new(false));
+        // Ticks the camcoder completed
+        let progress = Arc::new(AtomicUsize::new(0));
+        let shared = self.shared.clone();
+        let thread_abandoned = abandoned.clone();
+        let thread_progress = progress.clone();
+        // Also completes the segments a failed predecessor left in progress
+        let generation = self.shared.renditions.start_generation();
+        let thread = thread::Builder::new()
+            .name("camcoder".to_owned())
+            .spawn(move || {
+                let result = run_camcoder(
+                    &shared,
+                    &thread_abandoned,
+                    &thread_progress,
+                    generation,
+                    discontinuity,
+                );
+                let _ = exit_sender.send(result.map_err(Failure::from));
+            })
+            .map_err(|err| Failure::new(format!("Failed to spawn camcoder thread: {}", err)))?;
+
+        let mut ticks = 0;
+        let mut stall_warned = false;
+        // Opening the source and creating the encoders may take a while, so
+        // the stall clock starts at the first completed tick
+        let mut progressed_at = None;
+        let stall_timeout = Duration::from_millis(
+            self.shared.ts_duration_ms * self.config.stall_after_target_durations as u64,
+        );
+        loop {
+            match exit_receiver.recv_timeout(Duration::from_millis(CHECK_INTERVAL_MS)) {
+                Ok(result) => {
+                    let _ = thread.join();
+                    return result;
+                }
+                // The thread ended without reporting, so it panicked
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(Failure::new(match thread.join() {
+                        Ok(()) => "camcoder thread exited".to_owned(),
+                        Err(payload) => format!("panicked: {}", panic_message(&*payload)),
+                    }));
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+            // Segments may be as long as the GOP of passthrough input, so
+            // progress is told by ticks rather than by segments
+            let current_ticks = progress.load(Ordering::Relaxed);
+            if current_ticks != ticks {
+                ticks = current_ticks;
+                progressed_at = Some(Instant::now());
+                stall_warned = false;
+            }
+            let stalled_for = match progressed_at {
+                Some(progressed_at) => progressed_at.elapsed(),
+                None => continue,
+            };
+            if stalled_for > stall_timeout && !self.shared.reopenable {
+                // A recreated camcoder couldn't read the input either, so a
+                // slow writer is waited for rather than ending the stream
+                if !stall_warned {
+                    warn!(
+                        "No progress for {} ms, waiting for {}",
+                        millis(stalled_for),
+                        self.shared.source.path
+                    );
+                    stall_warned = true;
+                }
+            } else if stalled_for > stall_timeout {
+                // The thread can't be killed. It exits at its next tick, if
+                // it ever gets there, and can't touch the playlists anymore.
+                abandoned.store(true, Ordering::Relaxed);
+                self.shared.renditions.start_generation();
+                return Err(Failure::new(format!(
+                    "no progress for {} ms",
+                    millis(stalled_for)
+                )));
+            }
+        }
+    }
+}
+
+fn run_camcoder(
+    shared: &Shared,
+    abandoned: &AtomicBool,
+    progress: &AtomicUsize,
+    generation: u64,
+    discontinuity: bool,
+) -> Result<(), Box<Error>> {
+    let _alive = Health::camcoder_alive(&shared.health);
+    let encoder = (*shared.renditions.video.encoder.load()).clone();
+    let overlay = lock(&shared.overlay).clone();
+    let (width, height) = (shared.renditions.video.width, shared.renditions.video.height);
+    let mut camcoder: Box<Pipeline> = if shared.source.kind == SourceKind::Passthrough {
+        Box::new(Remuxer::new(
+            shared.renditions.clone(),
+            shared.metrics.clone(),
+            Demuxer::open(&shared.source, width, height)?,
+            encoder.frame_duration_ms,
+            shared.ts_duration_ms,
+            generation,
+            discontinuity,
+        )?)
+    } else {
+        Box::new(Camcorder::new(
+            shared.renditions.clone(),
+            shared.metrics.clone(),
+            source::open(&shared.source, width, height, &overlay)?,
+            &encoder,
+            shared.ts_duration_ms,
+            generation,
+            discontinuity,
+        )?)
+    };
+    let mut core = Core::new()?;
+    let handle = core.handle();
+    let interval = Interval::new(Duration::from_millis(encoder.frame_duration_ms), &handle)?;
+    let ended = Cell::new(false);
+    core.run(
+        interval
+            .map_err(|err| -> Box<Error> { Box::new(err) })
+            .take_while(|_| {
+                Ok(!shared.stop.load(Ordering::Relaxed) && !abandoned.load(Ordering::Relaxed)
+                    && !ended.get())
+            })
+            .for_each(|_| -> Result<(), Box<Error>> {
+                for command in lock(&shared.commands).try_iter() {
+                    if let Command::Reconfigure(_, ref overlay) = command {
+                        *lock(&shared.overlay) = overlay.clone();
+                    }
+                    camcoder.handle(command)?;
+                }
+                ended.set(!camcoder.run()?);
+                progress.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }),
+    )?;
+    if ended.get() {
+        info!("Source ended");
+    }
+    // An abandoned camcoder leaves the playlists to its successor
+    if (ended.get() || shared.stop.load(Ordering::Relaxed)) && !abandoned.load(Ordering::Relaxed) {
+        camcoder.finish()?;
+    }
+    Ok(())
+}
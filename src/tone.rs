@@ -0,0 +1,72 @@
+use std::cmp::max;
+use std::f64::consts::PI;
+
+const TONE_AMPLITUDE: f64 = 0.1;
+const BEEP_HZ: f64 = 1000.0;
+const BEEP_AMPLITUDE: f64 = 0.5;
+const BEEP_DURATION_MS: u64 = 100;
+
+pub struct ToneGenerator {
+    sample_rate: u64,
+    channels: usize,
+    tone_hz: f64,
+    generated_samples: u64,
+    tone_phase: f64,
+    beep_phase: f64,
+}
+
+impl ToneGenerator {
+    pub fn new(sample_rate: u64, channels: usize, tone_hz: f64) -> ToneGenerator {
+        ToneGenerator {
+            sample_rate,
+            channels,
+            tone_hz,
+            generated_samples: 0,
+            tone_phase: 0.0,
+            beep_phase: 0.0,
+        }
+    }
+
+    // Starts the tone at start_ms of the timeline instead of 0
+    pub fn skip_to(&mut self, start_ms: u64) {
+        self.generated_samples = max(self.generated_samples, start_ms * self.sample_rate / 1000);
+    }
+
+    // Generates planar samples up to end_ms. wall_clock_ms is the wall clock time
+    // (milliseconds of the current second) drawn on the video frame presented at
+    // start_ms, so the beep starts exactly when the overlay shows a new second.
+    pub fn generate(
+        &mut self,
+        planes: &mut Vec<Vec<f32>>,
+        start_ms: u64,
+        end_ms: u64,
+        wall_clock_ms: u64,
+    ) {
+        let first_sample = self.generated_samples;
+        let last_sample = end_ms * self.sample_rate / 1000;
+        planes.resize(self.channels, Vec::new());
+        for plane in planes.iter_mut() {
+            plane.clear();
+        }
+        if last_sample <= first_sample {
+            return;
+        }
+
+        for sample in first_sample..last_sample {
+            let media_ms = sample * 1000 / self.sample_rate;
+            let ms_in_second = (wall_clock_ms + 1000 + media_ms - start_ms) % 1000;
+            let mut value = TONE_AMPLITUDE * (2.0 * PI * self.tone_phase).sin();
+            if ms_in_second < BEEP_DURATION_MS {
+                value += BEEP_AMPLITUDE * (2.0 * PI * self.beep_phase).sin();
+                self.beep_phase = (self.beep_phase + BEEP_HZ / self.sample_rate as f64).fract();
+            } else {
+                self.beep_phase = 0.0;
+            }
+            self.tone_phase = (self.tone_phase + self.tone_hz / self.sample_rate as f64).fract();
+            for plane in planes.iter_mut() {
+                plane.push(value as f32);
+            }
+        }
+        self.generated_samples = last_sample;
+    }
+}
@@ -0,0 +1,391 @@
+use config::{SourceConfig, SourceKind};
+use encoder::Picture;
+use error::MediaError;
+use source::{Frame, FrameSource};
+use std::fs::File;
+use std::io::{self, stdin, BufRead, BufReader, Read};
+
+const Y4M_MAGIC: &str = "YUV4MPEG2";
+// Header and frame lines are short, anything longer is not YUV4MPEG2
+const MAX_LINE_BYTES: u64 = 1024;
+const SUPPORTED_COLORSPACES: &[&str] = &["420", "420jpeg", "420paldv", "420mpeg2"];
+
+fn invalid(message: String) -> MediaError {
+    MediaError::InvalidInput(message)
+}
+
+fn open(path: &str) -> Result<Box<BufRead>, MediaError> {
+    if path == "-" {
+        return Ok(Box::new(BufReader::new(stdin())));
+    }
+    let file = File::open(path).map_err(|err| MediaError::Io("open source.path", err))?;
+    Ok(Box::new(BufReader::new(file)))
+}
+
+// Reads a line without the newline. Returns None at the end of input, where
+// a line cut short is dropped like a frame cut short.
+fn read_line(reader: &mut BufRead) -> Result<Option<String>, MediaError> {
+    let mut line = Vec::new();
+    Read::take(&mut *reader, MAX_LINE_BYTES)
+        .read_until(b'\n', &mut line)
+        .map_err(|err| MediaError::Io("read source.path", err))?;
+    if line.pop() != Some(b'\n') {
+        if line.len() as u64 + 1 >= MAX_LINE_BYTES {
+            return Err(invalid("line too long".to_owned()));
+        }
+        return Ok(None);
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| invalid("header is not ASCII".to_owned()))
+}
+
+#[derive(Clone, Copy)]
+struct FrameRate {
+    num: u64,
+    den: u64,
+}
+
+fn parse_frame_rate(value: &str) -> Option<FrameRate> {
+    let mut parts = value.splitn(2, ':');
+    let num = parts.next()?.parse().ok()?;
+    let den = parts.next()?.parse().ok()?;
+    if num == 0 || den == 0 {
+        return None;
+    }
+    Some(FrameRate { num, den })
+}
+
+// Checks a YUV4MPEG2 stream header against the encoder size and returns the
+// frame rate.
+fn parse_header(line: &str, width: usize, height: usize) -> Result<FrameRate, MediaError> {
+    let mut params = line.split(' ').filter(|param| !param.is_empty());
+    if params.next() != Some(Y4M_MAGIC) {
+        return Err(invalid("not a YUV4MPEG2 stream".to_owned()));
+    }
+    let mut size = (None, None);
+    let mut frame_rate = None;
+    for param in params {
+        let tag = match param.chars().next() {
+            Some(tag) => tag,
+            None => continue,
+        };
+        let value = &param[tag.len_utf8()..];
+        match tag {
+            'W' => size.0 = value.parse::<usize>().ok(),
+            'H' => size.1 = value.parse::<usize>().ok(),
+            'F' => frame_rate = parse_frame_rate(value),
+            'C' if !SUPPORTED_COLORSPACES.contains(&value) => {
+                return Err(invalid(format!(
+                    "colorspace C{} is not supported, only 8 bit 4:2:0 is",
+                    value
+                )));
+            }
+            _ => {}
+        }
+    }
+    match size {
+        (Some(w), Some(h)) if (w, h) == (width, height) => {}
+        (Some(w), Some(h)) => {
+            return Err(invalid(format!(
+                "stream is {}x{}, but encoder.width x encoder.height is {}x{}",
+                w, h, width, height
+            )));
+        }
+        _ => return Err(invalid("header has no valid W or H".to_owned())),
+    }
+    frame_rate.ok_or_else(|| invalid("header has no valid F".to_owned()))
+}
+
+// Reads YUV4MPEG2 or headerless I420 frames from a file, a named pipe or
+// stdin. Frames are dropped or repeated to follow the stream timeline.
+pub struct RawVideoSource {
+    path: String,
+    y4m: bool,
+    looping: bool,
+    reader: Box<BufRead>,
+    frame_rate: FrameRate,
+    picture: Picture,
+    // Across loops, so that timestamps keep increasing
+    frames_read: u64,
+}
+
+impl RawVideoSource {
+    pub fn new(
+        config: &SourceConfig,
+        width: usize,
+        height: usize,
+    ) -> Result<RawVideoSource, MediaError> {
+        let reader = open(&config.path)?;
+        RawVideoSource::with_reader(config, reader, width, height)
+    }
+
+    fn with_reader(
+        config: &SourceConfig,
+        mut reader: Box<BufRead>,
+        width: usize,
+        height: usize,
+    ) -> Result<RawVideoSource, MediaError> {
+        let y4m = config.kind == SourceKind::Y4m;
+        let frame_rate = if y4m {
+            let header = read_line(&mut *reader)?
+                .ok_or_else(|| invalid("input has no header".to_owned()))?;
+            parse_header(&header, width, height)?
+        } else {
+            let frame_rate = config.frame_rate.unwrap_or(0.0);
+            FrameRate {
+                num: (frame_rate * 1000.0).round() as u64,
+                den: 1000,
+            }
+        };
+        Ok(RawVideoSource {
+            path: config.path.clone(),
+            y4m,
+            looping: config.looping,
+            reader,
+            frame_rate,
+            picture: Picture::new(width, height),
+            frames_read: 0,
+        })
+    }
+
+    // Reads the next frame into the picture. Returns false at the end of
+    // input, where a frame cut short is dropped.
+    fn read_frame(&mut self) -> Result<bool, MediaError> {
+        if self.y4m {
+            match read_line(&mut *self.reader)? {
+                None => return Ok(false),
+                Some(ref line) if line == "FRAME" || line.starts_with("FRAME ") => {}
+                Some(line) => return Err(invalid(format!("expected FRAME, got {:?}", line))),
+            }
+        }
+        let (width, height) = (self.picture.width, self.picture.height);
+        let widths = [width, (width + 1) / 2, (width + 1) / 2];
+        let rows = [height, (height + 1) / 2, (height + 1) / 2];
+        for plane in 0..3 {
+            let stride = self.picture.strides[plane];
+            for row in 0..rows[plane] {
+                let start = row * stride;
+                let pixels = &mut self.picture.planes[plane][start..start + widths[plane]];
+                match self.reader.read_exact(pixels) {
+                    Ok(()) => {}
+                    Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                        return Ok(false)
+                    }
+                    Err(err) => return Err(MediaError::Io("read source.path", err)),
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    // Reopens the input to loop it. stdin can't be reopened.
+    fn rewind(&mut self) -> Result<bool, MediaError> {
+        if !self.looping || self.path == "-" {
+            return Ok(false);
+        }
+        self.reader = open(&self.path)?;
+        if self.y4m {
+            let header = read_line(&mut *self.reader)?
+                .ok_or_else(|| invalid("input has no header".to_owned()))?;
+            // The timeline keeps the frame rate of the first pass
+            parse_header(&header, self.picture.width, self.picture.height)?;
+        }
+        Ok(true)
+    }
+}
+
+impl FrameSource for RawVideoSource {
+    fn size(&self) -> (usize, usize) {
+        (self.picture.width, self.picture.height)
+    }
+
+    fn frame(&mut self, timestamp_ms: u64) -> Result<Option<Frame>, MediaError> {
+        // Input frame shown at timestamp_ms
+        let index = timestamp_ms * self.frame_rate.num / (1000 * self.frame_rate.den);
+        while self.frames_read <= index {
+            if !self.read_frame()? {
+                if !self.rewind()? {
+                    return Ok(None);
+                }
+                if !self.read_frame()? {
+                    return Err(invalid("input has no frames to loop".to_owned()));
+                }
+            }
+            self.frames_read += 1;
+        }
+        self.picture.timestamp_ms = timestamp_ms;
+        Ok(Some(Frame::I420(&self.picture)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::io::Cursor;
+    use std::process;
+
+    const WIDTH: usize = 4;
+    const HEIGHT: usize = 2;
+    // Y, U and V of a 4x2 frame
+    const FRAME_BYTES: usize = 8 + 2 + 2;
+
+    // A stream whose frames have every byte set to their index
+    fn stream(header: &str, frames: u8) -> Vec<u8> {
+        let mut data = format!("{}\n", header).into_bytes();
+        for frame in 0..frames {
+            data.extend_from_slice(b"FRAME\n");
+            data.extend_from_slice(&[frame; FRAME_BYTES]);
+        }
+        data
+    }
+
+    fn y4m_config(looping: bool, path: &str) -> SourceConfig {
+        SourceConfig {
+            kind: SourceKind::Y4m,
+            path: path.to_owned(),
+            looping,
+            frame_rate: None,
+        }
+    }
+
+    fn source(data: Vec<u8>) -> RawVideoSource {
+        let reader = Box::new(Cursor::new(data));
+        RawVideoSource::with_reader(&y4m_config(false, "-"), reader, WIDTH, HEIGHT).unwrap()
+    }
+
+    // Index of the input frame shown at timestamp_ms, None once the source ended
+    fn frame_at(source: &mut RawVideoSource, timestamp_ms: u64) -> Option<u8> {
+        match source.frame(timestamp_ms).unwrap() {
+            Some(Frame::I420(picture)) => {
+                assert_eq!(picture.timestamp_ms, timestamp_ms);
+                Some(picture.planes[0][0])
+            }
+            Some(Frame::Rgb { .. }) => panic!("expected an I420 frame"),
+            None => None,
+        }
+    }
+
+    fn invalid_message<T>(result: Result<T, MediaError>) -> String {
+        match result {
+            Err(MediaError::InvalidInput(message)) => message,
+            Err(err) => panic!("expected invalid input, got {}", err),
+            Ok(_) => panic!("expected invalid input"),
+        }
+    }
+
+    #[test]
+    fn parses_header() {
+        let header = "YUV4MPEG2 W4 H2 F30000:1001 Ip A1:1 C420jpeg";
+        let frame_rate = parse_header(header, 4, 2).unwrap();
+        assert_eq!((frame_rate.num, frame_rate.den), (30000, 1001));
+        // Extra spaces and unknown parameters are ignored
+        let frame_rate = parse_header("YUV4MPEG2  W4 H2 F25:1 XYSCSS=420JPEG", 4, 2).unwrap();
+        assert_eq!((frame_rate.num, frame_rate.den), (25, 1));
+    }
+
+    #[test]
+    fn rejects_invalid_headers() {
+        let message = invalid_message(parse_header("YUV4MPEG W4 H2 F25:1", 4, 2));
+        assert!(message.contains("not a YUV4MPEG2 stream"));
+        let message = invalid_message(parse_header("YUV4MPEG2 W4 H4 F25:1", 4, 2));
+        assert!(message.contains("stream is 4x4"));
+        let message = invalid_message(parse_header("YUV4MPEG2 W4 F25:1", 4, 2));
+        assert!(message.contains("no valid W or H"));
+        let message = invalid_message(parse_header("YUV4MPEG2 W4 H2 F25:0", 4, 2));
+        assert!(message.contains("no valid F"));
+        let message = invalid_message(parse_header("YUV4MPEG2 W4 H2 F25:1 C444", 4, 2));
+        assert!(message.contains("C444"));
+    }
+
+    #[test]
+    fn repeats_frames_of_slower_input() {
+        let mut source = source(stream("YUV4MPEG2 W4 H2 F2:1", 2));
+        assert_eq!(frame_at(&mut source, 0), Some(0));
+        assert_eq!(frame_at(&mut source, 250), Some(0));
+        assert_eq!(frame_at(&mut source, 499), Some(0));
+        assert_eq!(frame_at(&mut source, 500), Some(1));
+        assert_eq!(frame_at(&mut source, 750), Some(1));
+        assert_eq!(frame_at(&mut source, 1000), None);
+    }
+
+    #[test]
+    fn drops_frames_of_faster_input() {
+        let mut source = source(stream("YUV4MPEG2 W4 H2 F10:1", 10));
+        assert_eq!(frame_at(&mut source, 0), Some(0));
+        assert_eq!(frame_at(&mut source, 500), Some(5));
+        assert_eq!(frame_at(&mut source, 900), Some(9));
+        assert_eq!(frame_at(&mut source, 1000), None);
+    }
+
+    #[test]
+    fn reads_raw_i420_at_the_configured_frame_rate() {
+        let config = SourceConfig {
+            kind: SourceKind::Raw,
+            frame_rate: Some(4.0),
+            ..y4m_config(false, "-")
+        };
+        let data: Vec<u8> = (0..3u8).flat_map(|frame| vec![frame; FRAME_BYTES]).collect();
+        let mut source =
+            RawVideoSource::with_reader(&config, Box::new(Cursor::new(data)), WIDTH, HEIGHT)
+                .unwrap();
+        assert_eq!(frame_at(&mut source, 0), Some(0));
+        assert_eq!(frame_at(&mut source, 500), Some(2));
+        assert_eq!(frame_at(&mut source, 750), None);
+    }
+
+    #[test]
+    fn drops_frames_cut_short() {
+        let mut data = stream("YUV4MPEG2 W4 H2 F1:1", 1);
+        data.extend_from_slice(b"FRAME\n");
+        data.extend_from_slice(&[1; FRAME_BYTES - 1]);
+        let mut source = source(data);
+        assert_eq!(frame_at(&mut source, 0), Some(0));
+        assert_eq!(frame_at(&mut source, 1000), None);
+    }
+
+    #[test]
+    fn ends_at_frame_lines_cut_short() {
+        let mut data = stream("YUV4MPEG2 W4 H2 F1:1", 1);
+        data.extend_from_slice(b"FRA");
+        let mut source = source(data);
+        assert_eq!(frame_at(&mut source, 0), Some(0));
+        assert_eq!(frame_at(&mut source, 1000), None);
+    }
+
+    #[test]
+    fn rejects_garbage_instead_of_frame_lines() {
+        let mut data = stream("YUV4MPEG2 W4 H2 F1:1", 1);
+        data.extend_from_slice(b"GARBAGE\n");
+        let mut source = source(data);
+        assert_eq!(frame_at(&mut source, 0), Some(0));
+        let message = invalid_message(source.frame(1000));
+        assert!(message.contains("expected FRAME"));
+    }
+
+    #[test]
+    fn rejects_empty_input() {
+        let result = RawVideoSource::with_reader(
+            &y4m_config(false, "-"),
+            Box::new(Cursor::new(Vec::new())),
+            WIDTH,
+            HEIGHT,
+        );
+        assert!(invalid_message(result).contains("no header"));
+    }
+
+    #[test]
+    fn loops_files_with_increasing_timestamps() {
+        let path = env::temp_dir().join(format!("y4m-loop-test-{}.y4m", process::id()));
+        fs::write(&path, stream("YUV4MPEG2 W4 H2 F1:1", 2)).unwrap();
+        let config = y4m_config(true, path.to_str().unwrap());
+        let frames: Vec<_> = {
+            let mut source = RawVideoSource::new(&config, WIDTH, HEIGHT).unwrap();
+            (0..5).map(|second| frame_at(&mut source, second * 1000)).collect()
+        };
+        fs::remove_file(&path).unwrap();
+        assert_eq!(frames, vec![Some(0), Some(1), Some(0), Some(1), Some(0)]);
+    }
+}